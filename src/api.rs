@@ -0,0 +1,127 @@
+use std::sync::Arc;
+
+use actix_web::web::{Data, Path, Query};
+use actix_web::{get, post, Error, HttpRequest, HttpResponse};
+use futures::future::{self, Either};
+use futures::Future;
+use log::*;
+use ring::constant_time;
+use serde::Deserialize;
+
+use crate::config::AdminConfig;
+use crate::github::{self, message::Message};
+use crate::history::History;
+use crate::queue::JobId;
+use crate::state::AppState;
+
+const DEFAULT_LIMIT: usize = 50;
+const MAX_LIMIT: usize = 500;
+
+#[derive(Debug, Deserialize)]
+pub struct ListQuery {
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+}
+
+#[get("/jobs")]
+fn list_jobs(query: Query<ListQuery>, history: Data<Arc<History>>) -> HttpResponse {
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+    HttpResponse::Ok().json(history.list(query.offset, limit))
+}
+
+#[get("/jobs/{id}")]
+fn get_job(path: Path<(JobId,)>, history: Data<Arc<History>>) -> HttpResponse {
+    match history.get(path.0) {
+        Some(record) => HttpResponse::Ok().json(record),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+/// Removes a queued job or aborts a running one.
+#[post("/jobs/{id}/cancel")]
+fn cancel_job(
+    request: HttpRequest,
+    path: Path<(JobId,)>,
+    admin: Data<Option<AdminConfig>>,
+    state: Data<AppState>,
+) -> HttpResponse {
+    if !is_admin(&request, &admin) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let id = path.0;
+    info!("Cancelling job {}", id);
+    let cancelled = state
+        .job_queue
+        .cancel(|queued_job| queued_job.id == id, &state.report_sender);
+    if cancelled.queued + cancelled.running == 0 {
+        HttpResponse::NotFound().finish()
+    } else {
+        HttpResponse::Ok().finish()
+    }
+}
+
+#[get("/deliveries")]
+fn list_deliveries(
+    request: HttpRequest,
+    admin: Data<Option<AdminConfig>>,
+    state: Data<AppState>,
+) -> HttpResponse {
+    if !is_admin(&request, &admin) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    HttpResponse::Ok().json(state.deliveries.list())
+}
+
+/// Processes a stored webhook delivery again (without checking its signature or whether it's a
+/// duplicate).
+#[post("/deliveries/{id}/replay")]
+fn replay_delivery(
+    request: HttpRequest,
+    path: Path<(String,)>,
+    admin: Data<Option<AdminConfig>>,
+    state: Data<AppState>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    if !is_admin(&request, &admin) {
+        return Either::A(future::ok(HttpResponse::Unauthorized().finish()));
+    }
+    let delivery = match state.deliveries.get(&path.0) {
+        Some(delivery) => delivery,
+        None => return Either::A(future::ok(HttpResponse::NotFound().finish())),
+    };
+    let message = match serde_json::from_str::<Message>(&delivery.payload) {
+        Ok(message) => message,
+        Err(e) => {
+            error!("Failed to deserialize delivery {}: {}", delivery.id, e);
+            return Either::A(future::ok(HttpResponse::BadRequest().finish()));
+        }
+    };
+
+    info!("Replaying delivery id {}", delivery.id);
+    Either::B(github::process_message(message, &state).then(|r| match r {
+        Ok(()) => HttpResponse::Ok(),
+        Err(_) => HttpResponse::InternalServerError(),
+    }))
+}
+
+/// Checks the request's `Authorization: Bearer <token>` header against the configured admin token.
+/// The admin endpoints are disabled if no token is configured.
+fn is_admin(request: &HttpRequest, admin: &Option<AdminConfig>) -> bool {
+    let admin = match admin {
+        Some(admin) => admin,
+        None => return false,
+    };
+    request
+        .headers()
+        .get("Authorization")
+        .and_then(|authorization| authorization.to_str().ok())
+        .filter(|authorization| authorization.starts_with("Bearer "))
+        .map(|authorization| {
+            constant_time::verify_slices_are_equal(
+                authorization["Bearer ".len()..].as_bytes(),
+                admin.token.as_bytes(),
+            )
+            .is_ok()
+        })
+        .unwrap_or(false)
+}
@@ -0,0 +1,133 @@
+use std::sync::Arc;
+
+use actix_web::http::header;
+use actix_web::web::{Data, Path};
+use actix_web::{get, HttpResponse};
+use serde::Serialize;
+
+use crate::config::Repository;
+use crate::history::{History, JobResult};
+
+const LABEL: &str = "ixy-ci";
+
+/// Response format of shields.io's endpoint badges (https://shields.io/endpoint)
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct EndpointBadge {
+    schema_version: u32,
+    label: &'static str,
+    message: &'static str,
+    color: &'static str,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    /// Redirect to a static shields.io badge
+    Svg,
+    /// shields.io endpoint
+    Json,
+}
+
+/// Serves `<branch>.svg` (redirects to shields.io) and `<branch>.json` (shields.io endpoint) badges
+/// showing the latest test result of a branch.
+#[get("/{user}/{repo}/{file:.*}")]
+fn badge(path: Path<(String, String, String)>, history: Data<Arc<History>>) -> HttpResponse {
+    let (user, name, file) = path.into_inner();
+    let repository = Repository { user, name };
+
+    let (branch, format) = match parse_file(&file) {
+        Some(parsed) => parsed,
+        None => return HttpResponse::NotFound().finish(),
+    };
+    let (message, color) = status(history.latest_branch_result(&repository, branch));
+
+    match format {
+        Format::Json => HttpResponse::Ok()
+            .header(header::CACHE_CONTROL, "no-cache")
+            .json(EndpointBadge {
+                schema_version: 1,
+                label: LABEL,
+                message,
+                color,
+            }),
+        Format::Svg => HttpResponse::Found()
+            .header(header::CACHE_CONTROL, "no-cache")
+            .header(header::LOCATION, shields_url(message, color))
+            .finish(),
+    }
+}
+
+/// Splits the requested file into the branch (which may contain slashes) and the badge format.
+fn parse_file(file: &str) -> Option<(&str, Format)> {
+    if file.ends_with(".svg") {
+        Some((&file[..file.len() - 4], Format::Svg))
+    } else if file.ends_with(".json") {
+        Some((&file[..file.len() - 5], Format::Json))
+    } else {
+        None
+    }
+}
+
+/// Returns the badge's message and color for the latest result of a branch.
+fn status(result: Option<JobResult>) -> (&'static str, &'static str) {
+    match result {
+        Some(JobResult::Passed) => ("passing", "brightgreen"),
+        Some(JobResult::Failed) => ("failing", "red"),
+        Some(JobResult::Error) => ("error", "lightgrey"),
+        Some(JobResult::Cancelled) | None => ("unknown", "lightgrey"),
+    }
+}
+
+fn shields_url(message: &str, color: &str) -> String {
+    format!(
+        "https://img.shields.io/badge/{}-{}-{}.svg",
+        escape_shields(LABEL),
+        escape_shields(message),
+        color
+    )
+}
+
+/// Escapes the characters which have a special meaning in shields.io's static badge URLs.
+fn escape_shields(s: &str) -> String {
+    s.replace('-', "--").replace('_', "__").replace(' ', "_")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_file() {
+        assert_eq!(parse_file("master.svg"), Some(("master", Format::Svg)));
+        assert_eq!(parse_file("master.json"), Some(("master", Format::Json)));
+        assert_eq!(
+            parse_file("feature/v1.0.svg"),
+            Some(("feature/v1.0", Format::Svg))
+        );
+        assert_eq!(
+            parse_file("release/2019/10.json"),
+            Some(("release/2019/10", Format::Json))
+        );
+        assert_eq!(parse_file("master.png"), None);
+        assert_eq!(parse_file("master"), None);
+        assert_eq!(parse_file("master.svg.txt"), None);
+    }
+
+    #[test]
+    fn test_status() {
+        assert_eq!(status(Some(JobResult::Passed)), ("passing", "brightgreen"));
+        assert_eq!(status(Some(JobResult::Failed)), ("failing", "red"));
+        assert_eq!(status(Some(JobResult::Error)), ("error", "lightgrey"));
+        assert_eq!(status(Some(JobResult::Cancelled)), ("unknown", "lightgrey"));
+        assert_eq!(status(None), ("unknown", "lightgrey"));
+    }
+
+    #[test]
+    fn test_shields_url() {
+        assert_eq!(
+            shields_url("passing", "brightgreen"),
+            "https://img.shields.io/badge/ixy--ci-passing-brightgreen.svg"
+        );
+        assert_eq!(escape_shields("a-b_c d"), "a--b__c_d");
+    }
+}
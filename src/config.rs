@@ -1,10 +1,10 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
 use std::fmt::{self, Display, Formatter};
 use std::net::SocketAddr;
 use std::path::PathBuf;
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use url::Url;
 
 use crate::github;
@@ -15,18 +15,122 @@ pub struct Config {
     pub bind_address: SocketAddr,
     pub public_url: Url,
     pub job_queue_size: usize,
+    /// Whether a new job for a pull request or branch also cancels its running job (queued jobs
+    /// are always replaced)
+    #[serde(default)]
+    pub supersede_running_jobs: bool,
+    /// How many jobs are processed concurrently (each one uses its own set of VMs)
+    #[serde(default = "default_workers")]
+    pub workers: usize,
     pub log_directory: PathBuf,
+    /// Directory for ixy-ci's internal state (e.g. the job queue)
+    #[serde(default = "default_data_directory")]
+    pub data_directory: PathBuf,
+    /// How many webhook deliveries are kept to detect duplicates and for replaying
+    #[serde(default = "default_delivery_log_size")]
+    pub delivery_log_size: usize,
+    /// Enables the admin API endpoints
+    pub admin: Option<AdminConfig>,
     pub github: GitHubConfig,
-    pub openstack: OpenStackConfig,
+    /// Exactly one VM backend has to be configured
+    pub openstack: Option<OpenStackConfig>,
+    pub libvirt: Option<LibvirtConfig>,
+    pub netns: Option<NamespaceConfig>,
     pub test: TestConfig,
 }
 
+impl Config {
+    pub fn vm_backend(&self) -> Result<VmBackend, &'static str> {
+        match (&self.openstack, &self.libvirt, &self.netns) {
+            (Some(openstack), None, None) => Ok(VmBackend::OpenStack(openstack.clone())),
+            (None, Some(libvirt), None) => Ok(VmBackend::Libvirt(libvirt.clone())),
+            (None, None, Some(netns)) => Ok(VmBackend::Namespaces(netns.clone())),
+            (None, None, None) => Err("no VM backend configured"),
+            _ => Err("only one VM backend may be configured"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum VmBackend {
+    OpenStack(OpenStackConfig),
+    Libvirt(LibvirtConfig),
+    Namespaces(NamespaceConfig),
+}
+
+fn default_workers() -> usize {
+    1
+}
+
+fn default_data_directory() -> PathBuf {
+    PathBuf::from("data")
+}
+
+fn default_delivery_log_size() -> usize {
+    100
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Has to be sent as `Authorization: Bearer <token>` to use the admin API endpoints
+    pub token: String,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct GitHubConfig {
     pub webhook_secrets: HashMap<Repository, String>,
+    /// Whether webhooks signed only with the legacy SHA-1 `X-Hub-Signature` are accepted
+    #[serde(default)]
+    pub allow_sha1_signature: bool,
     pub bot_name: String,
     pub api_token: String,
+    /// Only needs to be changed for GitHub Enterprise (or tests)
+    #[serde(default = "default_api_url")]
+    pub api_url: String,
+    /// Base URL for raw file contents (used to fetch `ixy-ci.toml`)
+    #[serde(default = "default_raw_url")]
+    pub raw_url: Url,
+    /// Branches which are tested automatically on every push
+    #[serde(default)]
+    pub test_branches: HashMap<Repository, Vec<String>>,
+    /// Repositories whose pull requests are tested automatically when they're opened or updated
+    #[serde(default)]
+    pub test_pull_requests: HashSet<Repository>,
+    /// GitHub App which is used to report results via the Checks API
+    pub app: Option<GitHubAppConfig>,
+    /// Restricts who is allowed to start tests; everyone is allowed if this isn't configured
+    pub access: Option<AccessConfig>,
+}
+
+fn default_api_url() -> String {
+    "https://api.github.com".to_string()
+}
+
+fn default_raw_url() -> Url {
+    Url::parse("https://raw.githubusercontent.com").unwrap()
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccessConfig {
+    /// GitHub logins which are always allowed to start tests
+    #[serde(default)]
+    pub allowed_users: HashSet<String>,
+    /// Whether collaborators of the tested repository are allowed to start tests
+    #[serde(default)]
+    pub allow_collaborators: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GitHubAppConfig {
+    pub app_id: u64,
+    /// Path to the app's private key in DER format
+    pub private_key_path: PathBuf,
+    /// Installation ids of the app keyed by the user/organization it's installed for
+    pub installations: HashMap<String, u64>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -47,11 +151,73 @@ pub struct OpenStackConfig {
     pub project_domain: String,
 }
 
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LibvirtConfig {
+    #[serde(default = "default_libvirt_uri")]
+    pub uri: String,
+    /// qcow2 image the VMs are booted from; `ssh_login` has to be able to log in with
+    /// `private_key_path` and use `sudo` without a password
+    pub base_image: PathBuf,
+    /// Directory where the VMs' (copy-on-write) disks are created
+    pub image_directory: PathBuf,
+    /// libvirt network through which the VMs are reachable via SSH (needs DHCP)
+    #[serde(default = "default_management_network")]
+    pub management_network: String,
+    pub memory_mib: u32,
+    pub vcpus: u32,
+    pub private_key_path: PathBuf,
+    pub ssh_login: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NamespaceConfig {
+    /// Directory in which a working directory per namespace is created (acts as home directory)
+    pub work_directory: PathBuf,
+}
+
+fn default_libvirt_uri() -> String {
+    "qemu:///system".to_string()
+}
+
+fn default_management_network() -> String {
+    "default".to_string()
+}
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct TestConfig {
     pub packets: usize,
+    /// Upper bound for the number of packets requested via bot commands
+    #[serde(default = "default_max_packets")]
+    pub max_packets: usize,
     pub pci_addresses: PciAddresses,
+    #[serde(default)]
+    pub criteria: PcapCriteria,
+}
+
+fn default_max_packets() -> usize {
+    1_000_000
+}
+
+/// Thresholds which the analysis of the captured packets has to stay within
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PcapCriteria {
+    /// How many packets which don't look like they were sent by pktgen are tolerated
+    #[serde(default)]
+    pub max_malformed: usize,
+    /// How many packets with an already captured sequence number are tolerated
+    #[serde(default)]
+    pub max_duplicates: usize,
+    /// How many pairs of packets may be captured in the wrong order (unchecked if not set)
+    pub max_inversions: Option<u64>,
+    /// How far a packet may be captured from its place in the sequence (unchecked if not set)
+    pub max_displacement: Option<usize>,
+    /// Share of the sequence numbers between the lowest and the highest captured one which may be
+    /// missing (unchecked if not set)
+    pub max_loss_ratio: Option<f64>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -70,11 +236,86 @@ pub struct RepositoryConfig {
     pub pktgen: String,
     pub fwd: String,
     pub pcap: String,
+    /// Alternative commands which can be selected with `@ixy-ci test scenario=<name>`
+    #[serde(default)]
+    pub scenarios: HashMap<String, ScenarioConfig>,
+    /// Format of the packets sent by pktgen (defaults to the one of ixy's pktgen)
+    #[serde(default)]
+    pub packet_format: PacketFormat,
+}
+
+impl RepositoryConfig {
+    /// Returns the config with the commands of the given scenario (or `None` if it isn't defined).
+    pub fn with_scenario(&self, name: &str) -> Option<RepositoryConfig> {
+        let scenario = self.scenarios.get(name)?;
+        let mut config = self.clone();
+        if let Some(pktgen) = &scenario.pktgen {
+            config.pktgen = pktgen.clone();
+        }
+        if let Some(fwd) = &scenario.fwd {
+            config.fwd = fwd.clone();
+        }
+        if let Some(pcap) = &scenario.pcap {
+            config.pcap = pcap.clone();
+        }
+        Some(config)
+    }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize)]
+/// Commands which replace the default ones when testing a scenario
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScenarioConfig {
+    pub pktgen: Option<String>,
+    pub fwd: Option<String>,
+    pub pcap: Option<String>,
+}
+
+/// Layout of the frames sent by pktgen; the Ethernet and IPv4 headers (and the ports) always match
+/// the ones of ixy's pktgen
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PacketFormat {
+    pub protocol: Protocol,
+    /// Size of the frames (without FCS)
+    pub length: usize,
+    /// Bytes at the start of the payload; the rest of the payload (apart from the sequence number)
+    /// has to be zero
+    pub marker: String,
+    /// Offset of the 32 bit sequence number from the start of the frame
+    pub seq_offset: usize,
+    pub seq_endianness: Endianness,
+}
+
+impl Default for PacketFormat {
+    fn default() -> PacketFormat {
+        PacketFormat {
+            protocol: Protocol::Udp,
+            length: 60,
+            marker: "ixy".to_string(),
+            seq_offset: 56,
+            seq_endianness: Endianness::Little,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Protocol {
+    Udp,
+    Tcp,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Endianness {
+    Little,
+    Big,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
-#[serde(try_from = "String")]
+#[serde(try_from = "String", into = "String")]
 pub struct Repository {
     pub user: String,
     pub name: String,
@@ -96,6 +337,12 @@ impl TryFrom<String> for Repository {
     }
 }
 
+impl From<Repository> for String {
+    fn from(repository: Repository) -> String {
+        repository.to_string()
+    }
+}
+
 impl From<&github::message::Repository> for Repository {
     fn from(repository: &github::message::Repository) -> Repository {
         Repository {
@@ -0,0 +1,220 @@
+use std::sync::Arc;
+
+use actix_web::web::{Data, Query};
+use actix_web::{get, HttpResponse};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+use crate::config::Repository;
+use crate::history::{History, JobRecord, JobResult};
+use crate::queue::JobQueue;
+use crate::worker::{Job, TestTarget};
+
+const PAGE_SIZE: usize = 25;
+
+#[derive(Debug, Deserialize)]
+pub struct HistoryQuery {
+    #[serde(default)]
+    page: usize,
+}
+
+#[get("/")]
+fn index(job_queue: Data<Arc<JobQueue>>, history: Data<Arc<History>>) -> HttpResponse {
+    let mut running = String::new();
+    for record in history.running() {
+        running += &format!(
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+            record.id,
+            format_target(&record.repository, &record.target),
+            format_sha(&record.repository, &record.sha),
+            escape(&record.requested_by),
+            record
+                .phase
+                .map(|phase| escape(&format!("{:?}", phase)))
+                .unwrap_or_default(),
+            format_time(&record.started_at),
+        );
+    }
+
+    let mut queued = String::new();
+    for (position, queued_job) in job_queue.queued_jobs().iter().enumerate() {
+        let (target, sha, requested_by) = match &queued_job.job {
+            Job::TestPullRequest {
+                repository,
+                pull_request_id,
+                sha,
+                requested_by,
+                ..
+            } => (
+                format_target(repository, &TestTarget::PullRequest(*pull_request_id)),
+                format_sha(repository, sha),
+                escape(requested_by),
+            ),
+            Job::TestBranch {
+                repository,
+                branch,
+                sha,
+                requested_by,
+                ..
+            } => (
+                format_target(repository, &TestTarget::Branch(branch.clone())),
+                format_sha(repository, sha),
+                escape(requested_by),
+            ),
+            Job::Ping { repository, .. } => (
+                format!("ping ({})", escape(&repository.to_string())),
+                String::new(),
+                String::new(),
+            ),
+        };
+        queued += &format!(
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+            position + 1,
+            queued_job.id,
+            target,
+            sha,
+            requested_by,
+            format_time(&queued_job.queued_at),
+        );
+    }
+
+    page(
+        "ixy-ci",
+        &format!(
+            "<h2>Running</h2>\n\
+             <table>\n<tr><th>Job</th><th>Target</th><th>Commit</th><th>Requested by</th>\
+             <th>Phase</th><th>Started</th></tr>\n{}</table>\n\
+             <h2>Queue</h2>\n\
+             <table>\n<tr><th>Position</th><th>Job</th><th>Target</th><th>Commit</th>\
+             <th>Requested by</th><th>Queued</th></tr>\n{}</table>\n\
+             <p><a href=\"/history\">Past results</a></p>",
+            running, queued
+        ),
+    )
+}
+
+#[get("/history")]
+fn job_history(query: Query<HistoryQuery>, history: Data<Arc<History>>) -> HttpResponse {
+    // The page comes straight from the query string so it may be arbitrarily large
+    let offset = query.page.saturating_mul(PAGE_SIZE);
+    let (records, total) = history.finished(offset, PAGE_SIZE);
+
+    let mut rows = String::new();
+    for record in &records {
+        rows += &format_history_row(record);
+    }
+
+    let mut navigation = String::new();
+    if query.page > 0 {
+        navigation += &format!("<a href=\"/history?page={}\">Newer</a> ", query.page - 1);
+    }
+    if offset.saturating_add(PAGE_SIZE) < total {
+        navigation += &format!("<a href=\"/history?page={}\">Older</a>", query.page + 1);
+    }
+
+    page(
+        "ixy-ci history",
+        &format!(
+            "<h2>Past results</h2>\n\
+             <table>\n<tr><th>Job</th><th>Target</th><th>Commit</th><th>Requested by</th>\
+             <th>Started</th><th>Duration</th><th>Result</th><th>Logs</th></tr>\n{}</table>\n\
+             <p>{}</p>\n<p><a href=\"/\">Current jobs</a></p>",
+            rows, navigation
+        ),
+    )
+}
+
+fn format_history_row(record: &JobRecord) -> String {
+    let duration = record
+        .finished_at
+        .map(|finished_at| format!("{}s", (finished_at - record.started_at).num_seconds()))
+        .unwrap_or_default();
+    let result = match record.result {
+        Some(JobResult::Passed) => "passed".to_string(),
+        Some(JobResult::Cancelled) => "cancelled".to_string(),
+        Some(JobResult::Failed) | Some(JobResult::Error) => format!(
+            "<span title=\"{}\">{}</span>",
+            escape(record.error.as_ref().map(String::as_str).unwrap_or("")),
+            if record.result == Some(JobResult::Failed) {
+                "failed"
+            } else {
+                "error"
+            }
+        ),
+        None => String::new(),
+    };
+    let mut logs = String::new();
+    if let Some(log_file) = &record.log_file {
+        logs += &format!("<a href=\"/logs/{}\">log</a> ", escape(log_file));
+    }
+    if let Some(pcap_file) = &record.pcap_file {
+        logs += &format!("<a href=\"/logs/{}\">pcap</a>", escape(pcap_file));
+    }
+    format!(
+        "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td>\
+         <td>{}</td></tr>\n",
+        record.id,
+        format_target(&record.repository, &record.target),
+        format_sha(&record.repository, &record.sha),
+        escape(&record.requested_by),
+        format_time(&record.started_at),
+        duration,
+        result,
+        logs,
+    )
+}
+
+fn page(title: &str, body: &str) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(format!(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{0}</title>\n\
+         <style>table {{ border-collapse: collapse; }} td, th {{ border: 1px solid #ccc; \
+         padding: 4px 8px; }}</style>\n</head>\n<body>\n<h1>{0}</h1>\n{1}\n</body>\n</html>\n",
+            escape(title),
+            body
+        ))
+}
+
+fn format_target(repository: &Repository, target: &TestTarget) -> String {
+    match target {
+        TestTarget::PullRequest(id) => format!(
+            "<a href=\"https://github.com/{0}/pull/{1}\">{0}#{1}</a>",
+            escape(&repository.to_string()),
+            id
+        ),
+        TestTarget::Branch(branch) => format!(
+            "<a href=\"https://github.com/{0}/tree/{1}\">{0}:{1}</a>",
+            escape(&repository.to_string()),
+            escape(branch)
+        ),
+    }
+}
+
+fn format_sha(repository: &Repository, sha: &str) -> String {
+    format!(
+        "<a href=\"https://github.com/{}/commit/{}\"><code>{}</code></a>",
+        escape(&repository.to_string()),
+        escape(sha),
+        escape(&sha.chars().take(7).collect::<String>())
+    )
+}
+
+fn format_time(time: &DateTime<Utc>) -> String {
+    time.format("%Y-%m-%d %H:%M:%S UTC").to_string()
+}
+
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped += "&amp;",
+            '<' => escaped += "&lt;",
+            '>' => escaped += "&gt;",
+            '"' => escaped += "&quot;",
+            '\'' => escaped += "&#39;",
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
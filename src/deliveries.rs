@@ -0,0 +1,169 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use log::*;
+use serde::{Deserialize, Serialize};
+
+/// A webhook delivery as received from GitHub
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Delivery {
+    /// Value of the `X-GitHub-Delivery` header
+    pub id: String,
+    pub event: String,
+    pub received_at: DateTime<Utc>,
+    /// The raw request body
+    pub payload: String,
+}
+
+/// A delivery without its payload
+#[derive(Debug, Clone, Serialize)]
+pub struct DeliverySummary {
+    pub id: String,
+    pub event: String,
+    pub received_at: DateTime<Utc>,
+}
+
+/// The most recent webhook deliveries which is used to ignore deliveries which GitHub sends again
+/// (e.g. after a timeout) and to replay deliveries for debugging. It's persisted to disk on every
+/// change and only keeps the last `capacity` deliveries.
+pub struct DeliveryLog {
+    path: PathBuf,
+    capacity: usize,
+    /// Ordered from oldest to newest
+    deliveries: Mutex<VecDeque<Delivery>>,
+}
+
+impl DeliveryLog {
+    pub fn open(path: PathBuf, capacity: usize) -> Result<DeliveryLog, io::Error> {
+        let deliveries = match fs::read(&path) {
+            Ok(content) => serde_json::from_slice(&content)?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => VecDeque::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(DeliveryLog {
+            path,
+            capacity,
+            deliveries: Mutex::new(deliveries),
+        })
+    }
+
+    /// Records a delivery. Returns `false` if a delivery with the same id was already recorded.
+    pub fn record(&self, delivery: Delivery) -> bool {
+        let mut deliveries = self.deliveries.lock().unwrap();
+        if deliveries.iter().any(|d| d.id == delivery.id) {
+            return false;
+        }
+        deliveries.push_back(delivery);
+        while deliveries.len() > self.capacity {
+            deliveries.pop_front();
+        }
+        self.persist(&deliveries);
+        true
+    }
+
+    /// Forgets a delivery (e.g. because processing it failed) so that it isn't treated as a
+    /// duplicate if GitHub sends it again.
+    pub fn remove(&self, id: &str) {
+        let mut deliveries = self.deliveries.lock().unwrap();
+        deliveries.retain(|d| d.id != id);
+        self.persist(&deliveries);
+    }
+
+    pub fn get(&self, id: &str) -> Option<Delivery> {
+        let deliveries = self.deliveries.lock().unwrap();
+        deliveries.iter().find(|d| d.id == id).cloned()
+    }
+
+    /// Returns all stored deliveries (newest first).
+    pub fn list(&self) -> Vec<DeliverySummary> {
+        let deliveries = self.deliveries.lock().unwrap();
+        deliveries
+            .iter()
+            .rev()
+            .map(|d| DeliverySummary {
+                id: d.id.clone(),
+                event: d.event.clone(),
+                received_at: d.received_at,
+            })
+            .collect()
+    }
+
+    fn persist(&self, deliveries: &VecDeque<Delivery>) {
+        // Write to a temporary file first so that we never end up with a half-written log
+        let tmp_path = self.path.with_extension("tmp");
+        let result = serde_json::to_vec(deliveries)
+            .map_err(io::Error::from)
+            .and_then(|content| fs::write(&tmp_path, content))
+            .and_then(|_| fs::rename(&tmp_path, &self.path));
+        if let Err(e) = result {
+            error!("Failed to persist delivery log: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn delivery(id: &str) -> Delivery {
+        Delivery {
+            id: id.to_string(),
+            event: "issue_comment".to_string(),
+            received_at: Utc::now(),
+            payload: format!("{{\"id\": \"{}\"}}", id),
+        }
+    }
+
+    fn ids(log: &DeliveryLog) -> Vec<String> {
+        log.list().into_iter().map(|d| d.id).collect()
+    }
+
+    #[test]
+    fn test_capacity() {
+        let path = testing::temp_dir("deliveries", "capacity").join("deliveries.json");
+        let log = DeliveryLog::open(path, 2).unwrap();
+        assert!(log.record(delivery("1")));
+        assert!(log.record(delivery("2")));
+        assert!(log.record(delivery("3")));
+        assert_eq!(ids(&log), vec!["3", "2"]);
+        assert!(log.get("1").is_none());
+        // Evicted deliveries aren't recognized as duplicates anymore
+        assert!(log.record(delivery("1")));
+        assert_eq!(ids(&log), vec!["1", "3"]);
+    }
+
+    #[test]
+    fn test_duplicates() {
+        let path = testing::temp_dir("deliveries", "duplicates").join("deliveries.json");
+        let log = DeliveryLog::open(path, 10).unwrap();
+        assert!(log.record(delivery("1")));
+        assert!(!log.record(delivery("1")));
+
+        // A removed delivery can be recorded again
+        log.remove("1");
+        assert!(log.get("1").is_none());
+        assert!(log.record(delivery("1")));
+        assert!(!log.record(delivery("1")));
+        assert_eq!(ids(&log), vec!["1"]);
+    }
+
+    #[test]
+    fn test_reload() {
+        let path = testing::temp_dir("deliveries", "reload").join("deliveries.json");
+        let log = DeliveryLog::open(path.clone(), 10).unwrap();
+        log.record(delivery("1"));
+        log.record(delivery("2"));
+        log.record(delivery("3"));
+        log.remove("2");
+
+        let log = DeliveryLog::open(path, 10).unwrap();
+        assert_eq!(ids(&log), vec!["3", "1"]);
+        assert_eq!(log.get("1").unwrap().payload, "{\"id\": \"1\"}");
+        assert!(!log.record(delivery("3")));
+    }
+}
@@ -0,0 +1,293 @@
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use log::*;
+use snafu::{ensure, ResultExt};
+
+use crate::remote::{self, Log};
+
+/// How long a cancelled local command may take to shut down before it's killed
+const CANCEL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Executes the commands of a test on one of the spawned VMs (or wherever the test runs)
+pub trait Executor {
+    /// Installs the tools the tests rely on (git and runner).
+    fn install_tools(&mut self) -> Result<(), remote::Error> {
+        Ok(())
+    }
+
+    /// Executes a command and blocks until it has finished. The command is executed by a shell so
+    /// commands like `echo 123 && echo abc` are valid.
+    fn execute_command(&mut self, command: &str) -> Result<(), remote::Error>;
+
+    /// Starts a command which runs until it's cancelled. `env` is a shell snippet which is executed
+    /// beforehand (e.g. to set variables used in `command`).
+    fn execute_cancellable_command<'a>(
+        &'a mut self,
+        command: &str,
+        env: &str,
+    ) -> Result<Box<dyn Cancellable + 'a>, remote::Error>;
+
+    /// Relative paths are relative to the home directory.
+    fn upload_file(
+        &mut self,
+        local_path: &Path,
+        remote_path: &Path,
+        mode: i32,
+    ) -> Result<(), remote::Error>;
+
+    /// Relative paths are relative to the home directory.
+    fn download_file(&mut self, remote_path: &Path) -> Result<Vec<u8>, remote::Error>;
+
+    /// Returns all executed commands together with their output.
+    fn into_log(self: Box<Self>) -> Log;
+}
+
+pub trait Cancellable {
+    fn is_running(&mut self) -> bool;
+    /// Stops the command and records its output in the log.
+    fn cancel(self: Box<Self>) -> Result<(), remote::Error>;
+}
+
+/// Executes commands as local processes in `directory` (which also acts as home directory). If a
+/// network namespace is given, cancellable commands (i.e. the applications under test) are started
+/// inside of it while all other commands (which usually need internet access to clone and build
+/// the repository) use the host's network.
+pub struct LocalExecutor {
+    directory: PathBuf,
+    namespace: Option<String>,
+    cancel_timeout: Duration,
+    log: Log,
+}
+
+impl LocalExecutor {
+    pub fn new(directory: PathBuf, namespace: Option<String>) -> LocalExecutor {
+        LocalExecutor {
+            directory,
+            namespace,
+            cancel_timeout: CANCEL_TIMEOUT,
+            log: Vec::new(),
+        }
+    }
+}
+
+impl Executor for LocalExecutor {
+    fn execute_command(&mut self, command: &str) -> Result<(), remote::Error> {
+        self.log.push((command.to_string(), String::new()));
+
+        debug!("executing local command: {}", command);
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(format!("exec 2>&1; {}", command))
+            .current_dir(&self.directory)
+            .env("HOME", &self.directory)
+            .output()
+            .context(remote::Io)?;
+
+        // We pushed to log at the start so this can't fail
+        self.log.last_mut().unwrap().1 = String::from_utf8_lossy(&output.stdout).into_owned();
+        ensure!(output.status.success(), remote::NonZeroReturn { command });
+        Ok(())
+    }
+
+    fn execute_cancellable_command<'a>(
+        &'a mut self,
+        command: &str,
+        env: &str,
+    ) -> Result<Box<dyn Cancellable + 'a>, remote::Error> {
+        self.log.push((command.to_string(), String::new()));
+
+        let command = format!("exec 2>&1; {}; {}", env, command);
+        debug!("Executing cancellable local command: {}", command);
+        // `setsid` puts the command into its own process group so that we can stop all of its
+        // descendants (e.g. of sudo) at once
+        let mut process = Command::new("setsid");
+        if let Some(namespace) = &self.namespace {
+            process.args(&["ip", "netns", "exec", namespace]);
+        }
+        let mut child = process
+            .args(&["sh", "-c", &command])
+            .current_dir(&self.directory)
+            .env("HOME", &self.directory)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .spawn()
+            .context(remote::Io)?;
+
+        // Read the output continuously so that the command never blocks on a full pipe
+        let mut stdout = child.stdout.take().unwrap();
+        let output = thread::spawn(move || {
+            let mut output = Vec::new();
+            let _ = stdout.read_to_end(&mut output);
+            String::from_utf8_lossy(&output).into_owned()
+        });
+
+        Ok(Box::new(LocalCommand {
+            child,
+            output,
+            cancel_timeout: self.cancel_timeout,
+            log: &mut self.log,
+        }))
+    }
+
+    fn upload_file(
+        &mut self,
+        local_path: &Path,
+        remote_path: &Path,
+        mode: i32,
+    ) -> Result<(), remote::Error> {
+        let remote_path = self.directory.join(remote_path);
+        debug!(
+            "Copying file: {} -> {}",
+            local_path.display(),
+            remote_path.display()
+        );
+        let mut local_file = File::open(local_path).context(remote::Io)?;
+        let mut remote_file = File::create(&remote_path).context(remote::Io)?;
+        io::copy(&mut local_file, &mut remote_file).context(remote::Io)?;
+        fs::set_permissions(&remote_path, fs::Permissions::from_mode(mode as u32))
+            .context(remote::Io)
+    }
+
+    fn download_file(&mut self, remote_path: &Path) -> Result<Vec<u8>, remote::Error> {
+        let remote_path = self.directory.join(remote_path);
+        debug!("Reading file {}", remote_path.display());
+        fs::read(remote_path).context(remote::Io)
+    }
+
+    fn into_log(self: Box<Self>) -> Log {
+        self.log
+    }
+}
+
+struct LocalCommand<'a> {
+    child: Child,
+    output: JoinHandle<String>,
+    cancel_timeout: Duration,
+    log: &'a mut Log,
+}
+
+impl Cancellable for LocalCommand<'_> {
+    fn is_running(&mut self) -> bool {
+        match self.child.try_wait() {
+            Ok(status) => status.is_none(),
+            Err(_) => false,
+        }
+    }
+
+    fn cancel(mut self: Box<Self>) -> Result<(), remote::Error> {
+        if self.is_running() {
+            // Same signal as runner sends so that the applications can shut down gracefully
+            self.signal_group("INT")?;
+            let deadline = Instant::now() + self.cancel_timeout;
+            while self.is_running() && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(100));
+            }
+            if self.is_running() {
+                warn!(
+                    "Killing local command which didn't stop within {:?}",
+                    self.cancel_timeout
+                );
+                self.signal_group("KILL")?;
+            }
+        }
+        self.child.wait().context(remote::Io)?;
+
+        let LocalCommand { output, log, .. } = *self;
+        // We pushed to log at the start so this can't fail
+        log.last_mut().unwrap().1 = output.join().unwrap_or_default();
+        Ok(())
+    }
+}
+
+impl LocalCommand<'_> {
+    fn signal_group(&self, signal: &str) -> Result<(), remote::Error> {
+        // The command has been started by `setsid` so its pid is also its process group id
+        Command::new("kill")
+            .args(&[
+                &format!("-{}", signal),
+                "--",
+                &format!("-{}", self.child.id()),
+            ])
+            .status()
+            .context(remote::Io)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn executor(name: &str) -> LocalExecutor {
+        LocalExecutor::new(testing::temp_dir("executor", name), None)
+    }
+
+    #[test]
+    fn test_execute_command() {
+        let mut executor = executor("execute");
+        executor
+            .execute_command("echo 123 && echo abc >&2")
+            .unwrap();
+        assert!(executor.execute_command("exit 1").is_err());
+
+        let log = Box::new(executor).into_log();
+        assert_eq!(log.len(), 2);
+        assert_eq!(log[0].0, "echo 123 && echo abc >&2");
+        assert_eq!(log[0].1, "123\nabc\n");
+    }
+
+    #[test]
+    fn test_cancellable_command() {
+        let mut executor = executor("cancel");
+        let mut command = executor
+            .execute_cancellable_command("echo $VALUE; sleep 60", "VALUE=42")
+            .unwrap();
+        assert!(command.is_running());
+        command.cancel().unwrap();
+
+        let log = Box::new(executor).into_log();
+        assert_eq!(log[0].1, "42\n");
+    }
+
+    #[test]
+    fn test_cancel_ignoring_sigint() {
+        let mut executor = executor("kill");
+        executor.cancel_timeout = Duration::from_secs(1);
+        let command = executor
+            .execute_cancellable_command("trap '' INT; echo started; sleep 60", "true")
+            .unwrap();
+        // Give the shell time to install the trap
+        thread::sleep(Duration::from_millis(500));
+        let start = Instant::now();
+        command.cancel().unwrap();
+        assert!(start.elapsed() < Duration::from_secs(30));
+
+        let log = Box::new(executor).into_log();
+        assert_eq!(log[0].1, "started\n");
+    }
+
+    #[test]
+    fn test_files() {
+        let mut executor = executor("files");
+        executor
+            .execute_command("mkdir dir && echo abc > dir/file")
+            .unwrap();
+        assert_eq!(
+            executor.download_file(Path::new("dir/file")).unwrap(),
+            b"abc\n"
+        );
+
+        let local_path = executor.directory.join("dir/file");
+        executor
+            .upload_file(&local_path, Path::new("copy"), 0o755)
+            .unwrap();
+        executor.execute_command("test -x copy").unwrap();
+    }
+}
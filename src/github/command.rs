@@ -0,0 +1,205 @@
+use snafu::Snafu;
+
+use crate::worker::TestOptions;
+
+#[derive(Debug, PartialEq, Eq, Snafu)]
+pub enum Error {
+    #[snafu(display("missing command"))]
+    MissingCommand,
+    #[snafu(display("unknown command `{}`", command))]
+    UnknownCommand { command: String },
+    #[snafu(display("`{}` doesn't take any arguments", command))]
+    UnexpectedArgument { command: String },
+    #[snafu(display("invalid argument `{}` (expected `key=value`)", argument))]
+    InvalidArgument { argument: String },
+    #[snafu(display("unknown argument `{}`", key))]
+    UnknownArgument { key: String },
+    #[snafu(display("invalid number of packets `{}` (expected 1 to {})", packets, max))]
+    InvalidPackets { packets: String, max: usize },
+}
+
+/// A command given to the bot in a comment like `@ixy-ci test packets=1000000`
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    /// Tests the current head of the pull request
+    Test(TestOptions),
+    /// Tests the current head again with the options of the previous test (unless overridden)
+    Retest(TestOptions),
+    /// Cancels the pull request's queued and running jobs
+    Cancel,
+    /// Reports the queue position or the last result
+    Status,
+    Help,
+    Ping,
+}
+
+/// Parses the first command addressed to `@<bot_name>` in `body`. Returns `None` if the bot isn't
+/// mentioned at all. At most `max_packets` packets may be requested.
+pub fn parse(body: &str, bot_name: &str, max_packets: usize) -> Option<Result<Command, Error>> {
+    let mention = format!("@{}", bot_name);
+    body.lines().find_map(|line| {
+        let mut words = line.split_whitespace();
+        words.find(|word| word.eq_ignore_ascii_case(&mention))?;
+        Some(parse_command(words, max_packets))
+    })
+}
+
+fn parse_command<'a, I: Iterator<Item = &'a str>>(
+    mut words: I,
+    max_packets: usize,
+) -> Result<Command, Error> {
+    let command = words.next().ok_or(Error::MissingCommand)?;
+    let arguments: Vec<_> = words.collect();
+    let no_arguments = |parsed| {
+        if arguments.is_empty() {
+            Ok(parsed)
+        } else {
+            Err(Error::UnexpectedArgument {
+                command: command.to_string(),
+            })
+        }
+    };
+    match command.to_ascii_lowercase().as_str() {
+        "test" => Ok(Command::Test(parse_options(&arguments, max_packets)?)),
+        "retest" => Ok(Command::Retest(parse_options(&arguments, max_packets)?)),
+        "cancel" => no_arguments(Command::Cancel),
+        "status" => no_arguments(Command::Status),
+        "help" => no_arguments(Command::Help),
+        "ping" => no_arguments(Command::Ping),
+        _ => Err(Error::UnknownCommand {
+            command: command.to_string(),
+        }),
+    }
+}
+
+fn parse_options(arguments: &[&str], max_packets: usize) -> Result<TestOptions, Error> {
+    let mut options = TestOptions::default();
+    for argument in arguments {
+        let mut split = argument.splitn(2, '=');
+        let (key, value) = match (split.next(), split.next()) {
+            (Some(key), Some(value)) if !key.is_empty() && !value.is_empty() => (key, value),
+            _ => {
+                return Err(Error::InvalidArgument {
+                    argument: argument.to_string(),
+                })
+            }
+        };
+        match key {
+            "packets" => {
+                let packets = value
+                    .parse()
+                    .ok()
+                    .filter(|&packets| packets > 0 && packets <= max_packets)
+                    .ok_or_else(|| Error::InvalidPackets {
+                        packets: value.to_string(),
+                        max: max_packets,
+                    })?;
+                options.packets = Some(packets);
+            }
+            "scenario" => options.scenario = Some(value.to_string()),
+            _ => {
+                return Err(Error::UnknownArgument {
+                    key: key.to_string(),
+                })
+            }
+        }
+    }
+    Ok(options)
+}
+
+/// Describes the available commands
+pub fn help(bot_name: &str) -> String {
+    format!(
+        "Available commands:\n\
+         - `@{bot} test [packets=<n>] [scenario=<name>]`: test the pull request\n\
+         - `@{bot} retest [packets=<n>] [scenario=<name>]`: test the pull request again with \
+         the options of the previous test\n\
+         - `@{bot} cancel`: cancel the pull request's queued and running tests\n\
+         - `@{bot} status`: show the queue position or the result of the last test\n\
+         - `@{bot} ping`: check whether I'm alive\n\
+         - `@{bot} help`: show this message",
+        bot = bot_name
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(body: &str) -> Option<Result<Command, Error>> {
+        super::parse(body, "ixy-ci", 1_000_000)
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("looks good to me"), None);
+        assert_eq!(parse("ping @ixy-ci-bot"), None);
+        assert_eq!(
+            parse("@ixy-ci test"),
+            Some(Ok(Command::Test(TestOptions::default())))
+        );
+        assert_eq!(
+            parse("Thanks!\n\n@IXY-CI Retest please-not-an-argument"),
+            Some(Err(Error::InvalidArgument {
+                argument: "please-not-an-argument".to_string()
+            }))
+        );
+        assert_eq!(parse("@ixy-ci cancel"), Some(Ok(Command::Cancel)));
+        assert_eq!(parse("hey @ixy-ci status"), Some(Ok(Command::Status)));
+        assert_eq!(parse("@ixy-ci help"), Some(Ok(Command::Help)));
+        assert_eq!(parse("@ixy-ci ping"), Some(Ok(Command::Ping)));
+        assert_eq!(parse("@ixy-ci"), Some(Err(Error::MissingCommand)));
+        assert_eq!(
+            parse("@ixy-ci deploy"),
+            Some(Err(Error::UnknownCommand {
+                command: "deploy".to_string()
+            }))
+        );
+        assert_eq!(
+            parse("@ixy-ci status now"),
+            Some(Err(Error::UnexpectedArgument {
+                command: "status".to_string()
+            }))
+        );
+    }
+
+    #[test]
+    fn test_parse_options() {
+        assert_eq!(
+            parse("@ixy-ci test packets=1000000 scenario=jumbo"),
+            Some(Ok(Command::Test(TestOptions {
+                packets: Some(1_000_000),
+                scenario: Some("jumbo".to_string()),
+            })))
+        );
+        assert_eq!(
+            parse("@ixy-ci retest scenario=jumbo"),
+            Some(Ok(Command::Retest(TestOptions {
+                packets: None,
+                scenario: Some("jumbo".to_string()),
+            })))
+        );
+        for packets in &["", "0", "-1", "many", "1000001", "18446744073709551616"] {
+            let expected = if packets.is_empty() {
+                Error::InvalidArgument {
+                    argument: "packets=".to_string(),
+                }
+            } else {
+                Error::InvalidPackets {
+                    packets: packets.to_string(),
+                    max: 1_000_000,
+                }
+            };
+            assert_eq!(
+                parse(&format!("@ixy-ci test packets={}", packets)),
+                Some(Err(expected))
+            );
+        }
+        assert_eq!(
+            parse("@ixy-ci test speed=fast"),
+            Some(Err(Error::UnknownArgument {
+                key: "speed".to_string()
+            }))
+        );
+    }
+}
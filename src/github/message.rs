@@ -16,6 +16,21 @@ pub enum Message {
         repository: Repository,
         comment: Comment,
     },
+    PullRequest {
+        action: PullRequestAction,
+        number: u64,
+        pull_request: PullRequest,
+        repository: Repository,
+        sender: Owner,
+    },
+    Push {
+        #[serde(rename = "ref")]
+        git_ref: String,
+        after: String,
+        deleted: bool,
+        repository: Repository,
+        sender: Owner,
+    },
 }
 
 #[derive(Debug, PartialEq, Eq, Deserialize)]
@@ -26,11 +41,23 @@ pub enum IssueCommentAction {
     Deleted,
 }
 
+#[derive(Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PullRequestAction {
+    Opened,
+    Reopened,
+    Synchronize,
+    #[serde(other)]
+    Other,
+}
+
 impl Message {
     pub fn github_event(&self) -> &str {
         match self {
             Message::Ping { .. } => "ping",
             Message::IssueComment { .. } => "issue_comment",
+            Message::PullRequest { .. } => "pull_request",
+            Message::Push { .. } => "push",
         }
     }
 
@@ -38,6 +65,8 @@ impl Message {
         match self {
             Message::Ping { repository, .. } => repository,
             Message::IssueComment { repository, .. } => repository,
+            Message::PullRequest { repository, .. } => repository,
+            Message::Push { repository, .. } => repository,
         }
         .into()
     }
@@ -49,6 +78,20 @@ pub struct Issue {
     pub number: u64,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct PullRequest {
+    pub head: Head,
+    pub user: Owner,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Head {
+    #[serde(rename = "ref")]
+    pub commit_ref: String,
+    pub sha: String,
+    pub user: Owner,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Repository {
     pub name: String,
@@ -63,4 +106,5 @@ pub struct Owner {
 #[derive(Debug, Deserialize)]
 pub struct Comment {
     pub body: String,
+    pub user: Owner,
 }
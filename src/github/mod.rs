@@ -1,18 +1,27 @@
+pub mod command;
 pub mod message;
 
+use actix_web::http::HeaderMap;
 use actix_web::web::{BytesMut, Data, Payload};
 use actix_web::{post, Error, HttpRequest, HttpResponse};
-use crossbeam_channel::Sender;
-use crossbeam_channel::TrySendError;
+use std::sync::Arc;
+
+use chrono::Utc;
 use futures::future::{self, Either};
-use futures::{Future, Stream};
+use futures::{Future, IntoFuture, Stream};
+use hubcaps::comments::CommentOptions;
 use hubcaps::Github;
 use log::*;
 use ring::hmac::VerificationKey;
 use ring::{digest, hmac};
 
-use crate::config::{self, GitHubConfig};
-use crate::worker::Job;
+use crate::config::{self, AccessConfig};
+use crate::deliveries::Delivery;
+use crate::history::{History, JobRecord, JobResult};
+use crate::queue::JobQueue;
+use crate::state::AppState;
+use crate::worker::{Job, TestOptions, TestTarget};
+use command::Command;
 use message::*;
 
 // TODO: Respond with "Sorry dave can't let you do that if @ixy-ci test outside of PR"
@@ -22,9 +31,7 @@ use message::*;
 fn webhook_service(
     request: HttpRequest,
     payload: Payload,
-    config: Data<GitHubConfig>,
-    github: Data<Github>,
-    job_sender: Data<Sender<Job>>,
+    state: Data<AppState>,
 ) -> impl Future<Item = HttpResponse, Error = Error> {
     payload
         .map_err(Error::from)
@@ -35,29 +42,53 @@ fn webhook_service(
         .and_then(move |body| {
             if let Ok(message) = serde_json::from_slice::<Message>(&body) {
                 let repo = message.repository();
-                if let Some(webhook_secret) = config.webhook_secrets.get(&repo) {
-                    if !check_request(&request, &body, &message, webhook_secret) {
+                if let Some(webhook_secret) = state.config.webhook_secrets.get(&repo) {
+                    if !check_request(
+                        &request,
+                        &body,
+                        &message,
+                        webhook_secret,
+                        state.config.allow_sha1_signature,
+                    ) {
                         Either::A(future::ok(HttpResponse::Unauthorized().finish()))
                     } else {
                         let delivery_id = request
                             .headers()
                             .get("X-GitHub-Delivery")
                             .and_then(|delivery_id| delivery_id.to_str().ok())
-                            .unwrap_or("unknown");
-                        info!("Processing delivery id {}", delivery_id);
-
-                        Either::B(
-                            process_message(
-                                message,
-                                &config.bot_name,
-                                github.get_ref().clone(),
-                                job_sender.get_ref().clone(),
-                            )
-                            .then(|r| match r {
-                                Ok(()) => HttpResponse::Ok(),
-                                Err(_) => HttpResponse::InternalServerError(),
-                            }),
-                        )
+                            .map(str::to_string);
+                        if let Some(delivery_id) = &delivery_id {
+                            let delivery = Delivery {
+                                id: delivery_id.clone(),
+                                event: message.github_event().to_string(),
+                                received_at: Utc::now(),
+                                payload: String::from_utf8_lossy(&body).into_owned(),
+                            };
+                            if !state.deliveries.record(delivery) {
+                                // GitHub sends deliveries again if we didn't respond in time
+                                info!("Ignoring duplicate delivery id {}", delivery_id);
+                                return Either::A(future::ok(HttpResponse::Ok().finish()));
+                            }
+                        }
+                        info!(
+                            "Processing delivery id {}",
+                            delivery_id
+                                .as_ref()
+                                .map(String::as_str)
+                                .unwrap_or("unknown")
+                        );
+
+                        let deliveries = Arc::clone(&state.deliveries);
+                        Either::B(process_message(message, &state).then(move |r| match r {
+                            Ok(()) => HttpResponse::Ok(),
+                            Err(_) => {
+                                // Process the delivery again if GitHub sends it again
+                                if let Some(delivery_id) = delivery_id {
+                                    deliveries.remove(&delivery_id);
+                                }
+                                HttpResponse::InternalServerError()
+                            }
+                        }))
                     }
                 } else {
                     error!("Failed to find webhook secret for {}", repo);
@@ -69,14 +100,87 @@ fn webhook_service(
         })
 }
 
-fn process_message(
+pub fn process_message(
     message: Message,
-    bot_name: &str,
-    github: Github,
-    job_sender: Sender<Job>,
+    state: &AppState,
 ) -> impl Future<Item = (), Error = Error> {
-    let job_future = match message {
-        Message::Ping { .. } => Either::B(future::ok(None)),
+    let config = &state.config;
+    let (github, job_queue, history, report_sender) = (
+        state.github.clone(),
+        Arc::clone(&state.job_queue),
+        Arc::clone(&state.history),
+        state.report_sender.clone(),
+    );
+    let job_future: Box<dyn Future<Item = Option<Job>, Error = Error>> = match message {
+        Message::Ping { .. } => Box::new(future::ok(None)),
+        Message::PullRequest {
+            action,
+            number,
+            pull_request,
+            repository,
+            sender,
+        } => {
+            let repository = config::Repository::from(&repository);
+            let test_pull_request = match action {
+                PullRequestAction::Opened
+                | PullRequestAction::Reopened
+                | PullRequestAction::Synchronize => config.test_pull_requests.contains(&repository),
+                PullRequestAction::Other => false,
+            };
+            if test_pull_request {
+                let (author, head) = (pull_request.user.login, pull_request.head);
+                Box::new(
+                    check_permission(config.access.as_ref(), &github, &repository, &author).map(
+                        move |allowed| {
+                            if allowed {
+                                Some(Job::TestPullRequest {
+                                    repository,
+                                    fork_user: head.user.login,
+                                    fork_branch: head.commit_ref,
+                                    pull_request_id: number,
+                                    sha: head.sha,
+                                    requested_by: sender.login,
+                                    options: TestOptions::default(),
+                                })
+                            } else {
+                                info!("Not testing {}#{} by {}", repository, number, author);
+                                None
+                            }
+                        },
+                    ),
+                )
+            } else {
+                Box::new(future::ok(None))
+            }
+        }
+        Message::Push {
+            git_ref,
+            after,
+            deleted,
+            repository,
+            sender,
+        } => {
+            let repository = config::Repository::from(&repository);
+            let branch = git_ref.trim_start_matches("refs/heads/");
+            let test_branch = !deleted
+                && config
+                    .test_branches
+                    .get(&repository)
+                    .map(|branches| branches.iter().any(|b| b == branch))
+                    .unwrap_or(false);
+            if test_branch {
+                info!("Push to {}:{} ({})", repository, branch, after);
+                Box::new(future::ok(Some(Job::TestBranch {
+                    branch: branch.to_string(),
+                    repository,
+                    sha: after,
+                    requested_by: sender.login,
+                    options: TestOptions::default(),
+                })))
+            } else {
+                Box::new(future::ok(None))
+            }
+        }
         Message::IssueComment {
             action,
             repository,
@@ -84,40 +188,123 @@ fn process_message(
             comment,
             ..
         } => {
-            if action == IssueCommentAction::Created {
-                if comment.body.contains(&format!("@{} test", bot_name)) {
-                    Either::A(
-                        github
-                            .repo(&repository.owner.login, &repository.name)
-                            .pulls()
-                            .get(issue.number)
-                            .get()
-                            .map(move |pull| {
-                                Some(Job::TestPullRequest {
-                                    repository: config::Repository {
-                                        user: repository.owner.login,
-                                        name: repository.name,
-                                    },
-                                    fork_user: pull.head.user.login,
-                                    fork_branch: pull.head.commit_ref,
-                                    pull_request_id: issue.number,
-                                })
-                            })
-                            .map_err(|_| Error::from(())), // TODO: ...
+            let repository = config::Repository::from(&repository);
+            let command = match action {
+                IssueCommentAction::Created => {
+                    command::parse(&comment.body, &config.bot_name, state.max_packets)
+                }
+                IssueCommentAction::Edited | IssueCommentAction::Deleted => None,
+            };
+            let (user, issue_id) = (comment.user.login, issue.number);
+            match command {
+                None => Box::new(future::ok(None)),
+                Some(Err(e)) => {
+                    info!("Invalid command by {} in {}: {}", user, repository, e);
+                    let body = format!(
+                        "Sorry @{}, I didn't understand that: {}.\n\n{}",
+                        user,
+                        e,
+                        command::help(&config.bot_name)
+                    );
+                    Box::new(if_permitted(
+                        config.access.as_ref(),
+                        github,
+                        repository,
+                        user,
+                        move |github, repository| reply(github, &repository, issue_id, body),
+                    ))
+                }
+                Some(Ok(Command::Ping)) => Box::new(if_permitted(
+                    config.access.as_ref(),
+                    github,
+                    repository,
+                    user,
+                    move |_, repository| {
+                        Ok(Some(Job::Ping {
+                            repository,
+                            issue_id,
+                        }))
+                    },
+                )),
+                Some(Ok(Command::Help)) => {
+                    let help = command::help(&config.bot_name);
+                    Box::new(if_permitted(
+                        config.access.as_ref(),
+                        github,
+                        repository,
+                        user,
+                        move |github, repository| reply(github, &repository, issue_id, help),
+                    ))
+                }
+                Some(Ok(Command::Status)) => {
+                    let status = status(&job_queue, &history, &repository, issue_id);
+                    Box::new(if_permitted(
+                        config.access.as_ref(),
+                        github,
+                        repository,
+                        user,
+                        move |github, repository| reply(github, &repository, issue_id, status),
+                    ))
+                }
+                Some(Ok(Command::Test(options))) => Box::new(start_test(
+                    config.access.as_ref(),
+                    github,
+                    repository,
+                    issue_id,
+                    user,
+                    options,
+                )),
+                Some(Ok(Command::Retest(options))) => {
+                    let previous = history
+                        .latest(&repository, &TestTarget::PullRequest(issue_id))
+                        .map(|record| record.options)
+                        .unwrap_or_default();
+                    Box::new(start_test(
+                        config.access.as_ref(),
+                        github,
+                        repository,
+                        issue_id,
+                        user,
+                        options.or(previous),
+                    ))
+                }
+                Some(Ok(Command::Cancel)) => {
+                    let (job_queue, report_sender) = (job_queue.clone(), report_sender.clone());
+                    Box::new(
+                        check_permission(config.access.as_ref(), &github, &repository, &user)
+                            .and_then(move |allowed| {
+                                if allowed {
+                                    let target = TestTarget::PullRequest(issue_id);
+                                    let cancelled = job_queue.cancel(
+                                        |queued_job| queued_job.job.tests(&repository, &target),
+                                        &report_sender,
+                                    );
+                                    Either::A(reply(
+                                        &github,
+                                        &repository,
+                                        issue_id,
+                                        format!(
+                                            "Cancelled {} queued test(s), aborting {} running \
+                                             test(s).",
+                                            cancelled.queued, cancelled.running
+                                        ),
+                                    ))
+                                } else {
+                                    info!(
+                                        "Refusing to cancel tests for {} in {}",
+                                        user, repository
+                                    );
+                                    Either::B(refuse(
+                                        &github,
+                                        &repository,
+                                        issue_id,
+                                        &user,
+                                        "cancel",
+                                    ))
+                                }
+                            }),
                     )
-                } else if comment.body.contains(&format!("@{} ping", bot_name)) {
-                    Either::B(future::ok(Some(Job::Ping {
-                        repository: config::Repository {
-                            user: repository.owner.login,
-                            name: repository.name,
-                        },
-                        issue_id: issue.number,
-                    })))
-                } else {
-                    Either::B(future::ok(None))
                 }
-            } else {
-                Either::B(future::ok(None))
             }
         }
     };
@@ -126,23 +313,203 @@ fn process_message(
             info!(
                 "Adding new job to queue {:?} (current queue size: {})",
                 job,
-                job_sender.len(),
+                job_queue.queue_size(),
             );
-            match job_sender.try_send(job) {
-                Ok(()) => {}
-                Err(TrySendError::Full(_)) => error!("Dropping job because queue is full"),
-                Err(TrySendError::Disconnected(_)) => panic!("Job queue disconnected"),
+            match job_queue.push(job, &report_sender) {
+                Ok(pushed) if pushed.replaced_same_commit => info!(
+                    "Job {} replaced a queued job for the same commit",
+                    pushed.id
+                ),
+                Ok(_) => {}
+                Err(e) => error!("Dropping job: {}", e),
             }
         }
     })
 }
 
+/// Checks whether `user` is allowed to start tests in `repository`.
+fn check_permission(
+    access: Option<&AccessConfig>,
+    github: &Github,
+    repository: &config::Repository,
+    user: &str,
+) -> Box<dyn Future<Item = bool, Error = Error>> {
+    match access {
+        // Everyone may start tests if no restrictions are configured
+        None => Box::new(future::ok(true)),
+        Some(access) if access.allowed_users.contains(user) => Box::new(future::ok(true)),
+        Some(access) if access.allow_collaborators => Box::new(
+            github
+                .repo(repository.user.clone(), repository.name.clone())
+                .collaborators()
+                .is_collaborator(user)
+                .map_err(|_| Error::from(())), // TODO: ...
+        ),
+        Some(_) => Box::new(future::ok(false)),
+    }
+}
+
+/// Performs a command which only answers `user` if they're allowed to start tests so that not
+/// everyone can make the bot comment on issues. Commands of other users are ignored.
+fn if_permitted<F, R>(
+    access: Option<&AccessConfig>,
+    github: Github,
+    repository: config::Repository,
+    user: String,
+    command: F,
+) -> impl Future<Item = Option<Job>, Error = Error>
+where
+    F: FnOnce(&Github, config::Repository) -> R,
+    R: IntoFuture<Item = Option<Job>, Error = Error>,
+{
+    check_permission(access, &github, &repository, &user).and_then(move |allowed| {
+        if allowed {
+            Either::A(command(&github, repository).into_future())
+        } else {
+            info!("Ignoring command by {} in {}", user, repository);
+            Either::B(future::ok(None))
+        }
+    })
+}
+
+/// Tests the pull request's current head if `user` is allowed to start tests.
+fn start_test(
+    access: Option<&AccessConfig>,
+    github: Github,
+    repository: config::Repository,
+    pull_request_id: u64,
+    user: String,
+    options: TestOptions,
+) -> impl Future<Item = Option<Job>, Error = Error> {
+    check_permission(access, &github, &repository, &user).and_then(move |allowed| {
+        if allowed {
+            Either::A(fetch_pull_request_job(
+                &github,
+                repository,
+                pull_request_id,
+                user,
+                options,
+            ))
+        } else {
+            info!("Refusing to start test for {} in {}", user, repository);
+            Either::B(refuse(
+                &github,
+                &repository,
+                pull_request_id,
+                &user,
+                "start",
+            ))
+        }
+    })
+}
+
+fn fetch_pull_request_job(
+    github: &Github,
+    repository: config::Repository,
+    pull_request_id: u64,
+    requested_by: String,
+    options: TestOptions,
+) -> impl Future<Item = Option<Job>, Error = Error> {
+    github
+        .repo(repository.user.clone(), repository.name.clone())
+        .pulls()
+        .get(pull_request_id)
+        .get()
+        .map(move |pull| {
+            Some(Job::TestPullRequest {
+                repository,
+                fork_user: pull.head.user.login,
+                fork_branch: pull.head.commit_ref,
+                pull_request_id,
+                sha: pull.head.sha,
+                requested_by,
+                options,
+            })
+        })
+        .map_err(|_| Error::from(())) // TODO: ...
+}
+
+/// Describes the queue position of the pull request's jobs or the result of its last test.
+fn status(
+    job_queue: &JobQueue,
+    history: &History,
+    repository: &config::Repository,
+    pull_request_id: u64,
+) -> String {
+    let target = TestTarget::PullRequest(pull_request_id);
+    let queued = job_queue
+        .queued_jobs()
+        .iter()
+        .position(|queued_job| queued_job.job.tests(repository, &target));
+    if let Some(position) = queued {
+        return format!("A test is queued at position {}.", position + 1);
+    }
+    match history.latest(repository, &target) {
+        Some(JobRecord {
+            sha,
+            finished_at: None,
+            ..
+        }) => format!("{} is currently being tested.", sha),
+        Some(JobRecord {
+            sha,
+            result: Some(result),
+            ..
+        }) => {
+            let result = match result {
+                JobResult::Passed => "passed",
+                JobResult::Failed => "failed",
+                JobResult::Error => "errored",
+                JobResult::Cancelled => "was cancelled",
+            };
+            format!("The last test of {} {}.", sha, result)
+        }
+        _ => "This pull request hasn't been tested yet.".to_string(),
+    }
+}
+
+/// Answers `user` that they aren't allowed to `action` tests.
+fn refuse(
+    github: &Github,
+    repository: &config::Repository,
+    issue_id: u64,
+    user: &str,
+    action: &str,
+) -> impl Future<Item = Option<Job>, Error = Error> {
+    reply(
+        github,
+        repository,
+        issue_id,
+        format!(
+            "Sorry @{}, you're not allowed to {} tests in this repository. \
+             Please ask one of the maintainers to {} them for you.",
+            user, action, action
+        ),
+    )
+}
+
+fn reply(
+    github: &Github,
+    repository: &config::Repository,
+    issue_id: u64,
+    body: String,
+) -> impl Future<Item = Option<Job>, Error = Error> {
+    github
+        .repo(repository.user.clone(), repository.name.clone())
+        .issues()
+        .get(issue_id)
+        .comments()
+        .create(&CommentOptions { body })
+        .map(|_| None)
+        .map_err(|_| Error::from(())) // TODO: ...
+}
+
 // This could be rewritten to a proper middleware but that doesn't really seem worth it atm.
 fn check_request(
     request: &HttpRequest,
     payload: &[u8],
     message: &Message,
     github_webhook_secret: &str,
+    allow_sha1_signature: bool,
 ) -> bool {
     let headers = request.headers();
 
@@ -155,15 +522,12 @@ fn check_request(
         error!("X-GitHub-Event didn't match deserialized message");
     }
 
-    let signature = headers
-        .get("X-Hub-Signature")
-        .and_then(|signature| hex::decode(&signature.as_bytes()[5..]).ok()) // Skip the "sha1=" prefix
-        .map(|signature| {
-            // ring verifies the hash in constant time
-            let v_key = VerificationKey::new(&digest::SHA1, github_webhook_secret.as_bytes());
-            hmac::verify(&v_key, payload, &signature).is_ok()
-        })
-        .unwrap_or(false);
+    let signature = check_signature(
+        headers,
+        payload,
+        github_webhook_secret,
+        allow_sha1_signature,
+    );
 
     if !signature {
         error!("Signature check failed");
@@ -172,12 +536,165 @@ fn check_request(
     event && signature
 }
 
+/// Verifies the payload's HMAC. `X-Hub-Signature-256` is preferred; the legacy SHA-1
+/// `X-Hub-Signature` is only used if it's allowed and no SHA-256 signature was sent.
+fn check_signature(
+    headers: &HeaderMap,
+    payload: &[u8],
+    github_webhook_secret: &str,
+    allow_sha1_signature: bool,
+) -> bool {
+    if let Some(signature) = headers.get("X-Hub-Signature-256") {
+        verify_signature(
+            signature.as_bytes(),
+            "sha256=",
+            &digest::SHA256,
+            payload,
+            github_webhook_secret,
+        )
+    } else if let Some(signature) = headers.get("X-Hub-Signature") {
+        if allow_sha1_signature {
+            verify_signature(
+                signature.as_bytes(),
+                "sha1=",
+                &digest::SHA1,
+                payload,
+                github_webhook_secret,
+            )
+        } else {
+            error!("Only a SHA-1 signature was sent but SHA-1 signatures aren't allowed");
+            false
+        }
+    } else {
+        error!("Request isn't signed");
+        false
+    }
+}
+
+fn verify_signature(
+    header: &[u8],
+    prefix: &str,
+    algorithm: &'static digest::Algorithm,
+    payload: &[u8],
+    github_webhook_secret: &str,
+) -> bool {
+    let signature = match parse_signature(header, prefix) {
+        Some(signature) => signature,
+        None => {
+            error!("Malformed signature header");
+            return false;
+        }
+    };
+    // ring verifies the hash in constant time
+    let v_key = VerificationKey::new(algorithm, github_webhook_secret.as_bytes());
+    hmac::verify(&v_key, payload, &signature).is_ok()
+}
+
+/// Parses a signature header like `sha256=<hex>`.
+fn parse_signature(header: &[u8], prefix: &str) -> Option<Vec<u8>> {
+    if header.starts_with(prefix.as_bytes()) {
+        hex::decode(&header[prefix.len()..]).ok()
+    } else {
+        None
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use actix_web::test::TestRequest;
+    use ring::hmac::SigningKey;
+
     use super::*;
 
+    const SECRET: &str = "secret";
+    const PAYLOAD: &str = r#"{
+        "zen": "Keep it logically awesome.",
+        "hook_id": 1,
+        "repository": { "name": "ixy.rs", "owner": { "login": "ixy-languages" } }
+    }"#;
+
+    fn sign(algorithm: &'static digest::Algorithm, prefix: &str, secret: &str) -> String {
+        let key = SigningKey::new(algorithm, secret.as_bytes());
+        format!(
+            "{}{}",
+            prefix,
+            hex::encode(hmac::sign(&key, PAYLOAD.as_bytes()).as_ref())
+        )
+    }
+
+    fn check(headers: &[(&str, &str)], allow_sha1_signature: bool) -> bool {
+        let mut request = TestRequest::default();
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        let message = serde_json::from_str(PAYLOAD).unwrap();
+        check_request(
+            &request.to_http_request(),
+            PAYLOAD.as_bytes(),
+            &message,
+            SECRET,
+            allow_sha1_signature,
+        )
+    }
+
     #[test]
     fn test_check_request() {
-        // TODO
+        let sha256 = sign(&digest::SHA256, "sha256=", SECRET);
+        let sha1 = sign(&digest::SHA1, "sha1=", SECRET);
+        let wrong_sha256 = sign(&digest::SHA256, "sha256=", "wrong secret");
+
+        assert!(check(
+            &[("X-GitHub-Event", "ping"), ("X-Hub-Signature-256", &sha256)],
+            false
+        ));
+        assert!(!check(
+            &[("X-GitHub-Event", "push"), ("X-Hub-Signature-256", &sha256)],
+            false
+        ));
+        assert!(!check(
+            &[
+                ("X-GitHub-Event", "ping"),
+                ("X-Hub-Signature-256", &wrong_sha256)
+            ],
+            false
+        ));
+        assert!(!check(&[("X-GitHub-Event", "ping")], true));
+
+        // SHA-1 is only a fallback
+        assert!(!check(
+            &[("X-GitHub-Event", "ping"), ("X-Hub-Signature", &sha1)],
+            false
+        ));
+        assert!(check(
+            &[("X-GitHub-Event", "ping"), ("X-Hub-Signature", &sha1)],
+            true
+        ));
+        assert!(!check(
+            &[
+                ("X-GitHub-Event", "ping"),
+                ("X-Hub-Signature-256", &wrong_sha256),
+                ("X-Hub-Signature", &sha1)
+            ],
+            true
+        ));
+    }
+
+    #[test]
+    fn test_malformed_signature() {
+        let sha256 = sign(&digest::SHA256, "sha256=", SECRET);
+        for signature in &["", "sha", "sha256", "sha256=", "sha256=xyz", &sha256[7..]] {
+            assert!(!check(
+                &[
+                    ("X-GitHub-Event", "ping"),
+                    ("X-Hub-Signature-256", signature)
+                ],
+                true
+            ));
+        }
+        // A SHA-256 signature in the SHA-1 header
+        assert!(!check(
+            &[("X-GitHub-Event", "ping"), ("X-Hub-Signature", &sha256)],
+            true
+        ));
     }
 }
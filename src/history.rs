@@ -0,0 +1,446 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use crate::config::Repository;
+use crate::queue::{JobId, QueuedJob};
+use crate::worker::{TestError, TestOptions, TestOutput, TestTarget};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    FetchConfig,
+    SpawnVms,
+    ConnectVms,
+    PrepareVms,
+    RunTest,
+    CheckPcap,
+    CleanUp,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobResult {
+    Passed,
+    /// The tested applications didn't behave correctly
+    Failed,
+    /// The test couldn't be performed (e.g. due to infrastructure problems)
+    Error,
+    /// The job was cancelled or superseded before it finished
+    Cancelled,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PhaseDuration {
+    pub phase: Phase,
+    pub duration_ms: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobRecord {
+    pub id: JobId,
+    pub repository: Repository,
+    pub target: TestTarget,
+    pub sha: String,
+    pub requested_by: String,
+    #[serde(default)]
+    pub options: TestOptions,
+    pub queued_at: DateTime<Utc>,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    /// The phase the job is currently in (only set while it's running)
+    pub phase: Option<Phase>,
+    pub phase_started_at: Option<DateTime<Utc>>,
+    pub phases: Vec<PhaseDuration>,
+    pub result: Option<JobResult>,
+    pub error_kind: Option<String>,
+    pub error: Option<String>,
+    pub log_file: Option<String>,
+    pub pcap_file: Option<String>,
+}
+
+impl JobRecord {
+    pub fn new(
+        queued_job: &QueuedJob,
+        repository: Repository,
+        target: TestTarget,
+        sha: String,
+        requested_by: String,
+        options: TestOptions,
+    ) -> JobRecord {
+        JobRecord {
+            id: queued_job.id,
+            repository,
+            target,
+            sha,
+            requested_by,
+            options,
+            queued_at: queued_job.queued_at,
+            started_at: Utc::now(),
+            finished_at: None,
+            phase: None,
+            phase_started_at: None,
+            phases: Vec::new(),
+            result: None,
+            error_kind: None,
+            error: None,
+            log_file: None,
+            pcap_file: None,
+        }
+    }
+
+    fn end_phase(&mut self, now: DateTime<Utc>) {
+        if let (Some(phase), Some(phase_started_at)) = (self.phase.take(), self.phase_started_at) {
+            self.phases.push(PhaseDuration {
+                phase,
+                duration_ms: (now - phase_started_at).num_milliseconds(),
+            });
+        }
+        self.phase_started_at = None;
+    }
+
+    fn set_result(&mut self, result: &Result<TestOutput, TestError>) {
+        let test_output = match result {
+            Ok(test_output) => {
+                self.result = Some(JobResult::Passed);
+                Some(test_output)
+            }
+            Err(e) => {
+                self.error_kind = Some(e.kind().to_string());
+                self.error = Some(e.to_string());
+                match e {
+                    TestError::PerformTest { test_output, .. } => {
+                        self.result = Some(JobResult::Failed);
+                        Some(test_output)
+                    }
+                    TestError::Cancelled | TestError::Superseded { .. } => {
+                        self.result = Some(JobResult::Cancelled);
+                        None
+                    }
+                    _ => {
+                        self.result = Some(JobResult::Error);
+                        None
+                    }
+                }
+            }
+        };
+        if let Some(test_output) = test_output {
+            self.log_file = Some(test_output.log_file.clone());
+            self.pcap_file = test_output.pcap_file.clone();
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    running: Vec<JobRecord>,
+    /// Ordered from oldest to newest
+    finished: Vec<JobRecord>,
+}
+
+/// Records of all test jobs. Finished jobs are appended to a JSON lines file.
+pub struct History {
+    path: PathBuf,
+    state: Mutex<State>,
+}
+
+impl History {
+    pub fn open(path: PathBuf) -> Result<History, io::Error> {
+        let mut state = State::default();
+        match fs::read_to_string(&path) {
+            Ok(content) => {
+                for line in content.lines().filter(|line| !line.trim().is_empty()) {
+                    match serde_json::from_str(line) {
+                        Ok(record) => state.finished.push(record),
+                        Err(e) => warn!("Skipping invalid job record: {}", e),
+                    }
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        info!("Loaded {} job records", state.finished.len());
+        Ok(History {
+            path,
+            state: Mutex::new(state),
+        })
+    }
+
+    pub fn start(&self, record: JobRecord) {
+        self.state.lock().unwrap().running.push(record);
+    }
+
+    pub fn enter_phase(&self, id: JobId, phase: Phase) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(record) = state.running.iter_mut().find(|record| record.id == id) {
+            let now = Utc::now();
+            record.end_phase(now);
+            record.phase = Some(phase);
+            record.phase_started_at = Some(now);
+        }
+    }
+
+    pub fn finish(&self, id: JobId, result: &Result<TestOutput, TestError>) {
+        let mut state = self.state.lock().unwrap();
+        let index = match state.running.iter().position(|record| record.id == id) {
+            Some(index) => index,
+            None => return,
+        };
+        let mut record = state.running.remove(index);
+        let now = Utc::now();
+        record.end_phase(now);
+        record.finished_at = Some(now);
+        record.set_result(result);
+
+        if let Err(e) = self.append(&record) {
+            error!("Failed to save job record: {}", e);
+        }
+        state.finished.push(record);
+    }
+
+    pub fn get(&self, id: JobId) -> Option<JobRecord> {
+        let state = self.state.lock().unwrap();
+        state
+            .running
+            .iter()
+            .chain(state.finished.iter())
+            .find(|record| record.id == id)
+            .cloned()
+    }
+
+    /// Returns running jobs followed by finished ones (newest first).
+    pub fn list(&self, offset: usize, limit: usize) -> Vec<JobRecord> {
+        let state = self.state.lock().unwrap();
+        state
+            .running
+            .iter()
+            .chain(state.finished.iter().rev())
+            .skip(offset)
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    pub fn running(&self) -> Vec<JobRecord> {
+        self.state.lock().unwrap().running.clone()
+    }
+
+    /// Returns a page of finished jobs (newest first) and the total number of finished jobs.
+    pub fn finished(&self, offset: usize, limit: usize) -> (Vec<JobRecord>, usize) {
+        let state = self.state.lock().unwrap();
+        let records = state
+            .finished
+            .iter()
+            .rev()
+            .skip(offset)
+            .take(limit)
+            .cloned()
+            .collect();
+        (records, state.finished.len())
+    }
+
+    /// Returns the result of the most recently finished (and not cancelled) test of the given
+    /// branch.
+    pub fn latest_branch_result(&self, repository: &Repository, branch: &str) -> Option<JobResult> {
+        let state = self.state.lock().unwrap();
+        state
+            .finished
+            .iter()
+            .rev()
+            .find(|record| {
+                record.repository == *repository
+                    && record.result != Some(JobResult::Cancelled)
+                    && match &record.target {
+                        TestTarget::Branch(b) => b == branch,
+                        TestTarget::PullRequest(_) => false,
+                    }
+            })
+            .and_then(|record| record.result)
+    }
+
+    /// Returns the most recent job (running or finished) which tested the given target.
+    pub fn latest(&self, repository: &Repository, target: &TestTarget) -> Option<JobRecord> {
+        let state = self.state.lock().unwrap();
+        state
+            .running
+            .iter()
+            .rev()
+            .chain(state.finished.iter().rev())
+            .find(|record| record.repository == *repository && record.target == *target)
+            .cloned()
+    }
+
+    fn append(&self, record: &JobRecord) -> Result<(), io::Error> {
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?
+            .write_all(line.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+
+    use super::*;
+    use crate::testing;
+    use crate::worker::Job;
+
+    fn open(name: &str) -> (History, PathBuf) {
+        let path = testing::temp_dir("history", name).join("history.jsonl");
+        (History::open(path.clone()).unwrap(), path)
+    }
+
+    fn repository() -> Repository {
+        Repository {
+            user: "ixy-languages".to_string(),
+            name: "ixy.rs".to_string(),
+        }
+    }
+
+    fn record(id: JobId, target: TestTarget) -> JobRecord {
+        let queued_job = QueuedJob {
+            id,
+            job: Job::Ping {
+                repository: repository(),
+                issue_id: 1,
+            },
+            queued_at: Utc::now(),
+            interrupted: false,
+        };
+        JobRecord::new(
+            &queued_job,
+            repository(),
+            target,
+            format!("{:04}", id),
+            "bobo1239".to_string(),
+            TestOptions::default(),
+        )
+    }
+
+    fn finished(history: &History, id: JobId, target: TestTarget, result: JobResult) {
+        let mut record = record(id, target);
+        record.result = Some(result);
+        history.state.lock().unwrap().finished.push(record);
+    }
+
+    fn branch(name: &str) -> TestTarget {
+        TestTarget::Branch(name.to_string())
+    }
+
+    #[test]
+    fn test_latest() {
+        let (history, _) = open("latest");
+        finished(&history, 1, branch("master"), JobResult::Passed);
+        finished(&history, 2, branch("master"), JobResult::Failed);
+        finished(&history, 3, branch("dev"), JobResult::Passed);
+        assert_eq!(
+            history.latest(&repository(), &branch("master")).unwrap().id,
+            2
+        );
+
+        // Running jobs are newer than all finished ones
+        history.start(record(4, branch("master")));
+        assert_eq!(
+            history.latest(&repository(), &branch("master")).unwrap().id,
+            4
+        );
+        assert!(history
+            .latest(&repository(), &TestTarget::PullRequest(3))
+            .is_none());
+    }
+
+    #[test]
+    fn test_latest_branch_result() {
+        let (history, _) = open("latest_branch_result");
+        finished(&history, 1, branch("master"), JobResult::Passed);
+        finished(&history, 2, branch("master"), JobResult::Failed);
+        finished(&history, 3, TestTarget::PullRequest(3), JobResult::Error);
+        finished(&history, 4, branch("master"), JobResult::Cancelled);
+        assert_eq!(
+            history.latest_branch_result(&repository(), "master"),
+            Some(JobResult::Failed)
+        );
+        assert_eq!(history.latest_branch_result(&repository(), "dev"), None);
+
+        let other = Repository {
+            user: "emmericp".to_string(),
+            name: "ixy".to_string(),
+        };
+        assert_eq!(history.latest_branch_result(&other, "master"), None);
+    }
+
+    #[test]
+    fn test_paging() {
+        let (history, _) = open("paging");
+        for id in 1..=5 {
+            finished(&history, id, branch("master"), JobResult::Passed);
+        }
+        history.start(record(6, branch("master")));
+
+        let ids = |records: Vec<JobRecord>| -> Vec<_> { records.iter().map(|r| r.id).collect() };
+        let (records, total) = history.finished(1, 2);
+        assert_eq!(ids(records), vec![4, 3]);
+        assert_eq!(total, 5);
+        let (records, total) = history.finished(4, 10);
+        assert_eq!(ids(records), vec![1]);
+        assert_eq!(total, 5);
+        assert_eq!(ids(history.list(0, 3)), vec![6, 5, 4]);
+    }
+
+    #[test]
+    fn test_end_phase() {
+        let mut record = record(1, branch("master"));
+        let now = Utc::now();
+        // Nothing happens outside of a phase
+        record.end_phase(now);
+        assert!(record.phases.is_empty());
+
+        record.phase = Some(Phase::RunTest);
+        record.phase_started_at = Some(now - Duration::milliseconds(1500));
+        record.end_phase(now);
+        assert_eq!(record.phase, None);
+        assert_eq!(record.phase_started_at, None);
+        assert_eq!(record.phases.len(), 1);
+        assert_eq!(record.phases[0].phase, Phase::RunTest);
+        assert_eq!(record.phases[0].duration_ms, 1500);
+    }
+
+    #[test]
+    fn test_reload() {
+        let (history, path) = open("reload");
+        history.start(record(1, branch("master")));
+        history.enter_phase(1, Phase::FetchConfig);
+        history.finish(1, &Err(TestError::Cancelled));
+        history.start(record(2, branch("master")));
+        let error = TestError::UnknownScenario {
+            scenario: "ixgbe".to_string(),
+        };
+        history.finish(2, &Err(error));
+        // Unknown jobs are ignored
+        history.finish(3, &Err(TestError::Cancelled));
+
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"invalid\": true}\n\n").unwrap();
+
+        let history = History::open(path).unwrap();
+        let (records, total) = history.finished(0, 10);
+        assert_eq!(total, 2);
+        assert_eq!(records[0].id, 2);
+        assert_eq!(records[0].result, Some(JobResult::Error));
+        assert_eq!(records[0].error_kind, Some("unknown_scenario".to_string()));
+        assert_eq!(records[1].id, 1);
+        assert_eq!(records[1].result, Some(JobResult::Cancelled));
+        assert_eq!(records[1].phases[0].phase, Phase::FetchConfig);
+        assert!(records[1].finished_at.is_some());
+        assert!(history.running().is_empty());
+    }
+}
@@ -0,0 +1,179 @@
+pub mod config;
+pub mod executor;
+pub mod remote;
+pub mod vm;
+
+mod api;
+mod badge;
+mod dashboard;
+mod deliveries;
+mod github;
+mod history;
+mod libvirt;
+mod netns;
+mod openstack;
+mod pcap_tester;
+mod publisher;
+mod queue;
+mod state;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+mod utility;
+mod worker;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::{fs, io, thread};
+
+use actix_files::Files;
+use actix_web::middleware::Logger;
+use actix_web::{web, App, HttpServer};
+use futures::{Future, Stream};
+use hubcaps::{Credentials, Github, InstallationTokenGenerator, JWTCredentials};
+
+use crate::config::Config;
+use crate::deliveries::DeliveryLog;
+use crate::history::History;
+use crate::publisher::{CheckRunStore, Publisher};
+use crate::queue::JobQueue;
+use crate::state::AppState;
+use crate::vm::VmProviderFactory;
+use crate::worker::Worker;
+
+/// Starts the workers and the web server and blocks until the server is stopped. Every worker gets
+/// its VM provider from `vm_providers`.
+pub fn run(config: Config, vm_providers: VmProviderFactory) -> io::Result<()> {
+    let user_agent = format!("{}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+    let github = Github::host(
+        config.github.api_url.clone(),
+        user_agent.clone(),
+        Credentials::Token(config.github.api_token.clone()),
+    )
+    .expect("failed to initialize GitHub");
+
+    // The Checks API is only available to GitHub Apps so we need a separately authenticated client
+    // for every installation
+    let mut check_clients = HashMap::new();
+    if let Some(app) = &config.github.app {
+        let private_key = fs::read(&app.private_key_path)?;
+        let jwt = JWTCredentials::new(app.app_id, private_key)
+            .expect("failed to parse GitHub App private key");
+        for (user, installation_id) in &app.installations {
+            let client = Github::host(
+                config.github.api_url.clone(),
+                user_agent.clone(),
+                Credentials::InstallationToken(InstallationTokenGenerator::new(
+                    *installation_id,
+                    jwt.clone(),
+                )),
+            )
+            .expect("failed to initialize GitHub App");
+            check_clients.insert(user.clone(), client);
+        }
+    }
+
+    fs::create_dir_all(&config.log_directory).expect("failed to create configured log directory");
+    fs::create_dir_all(&config.data_directory).expect("failed to create configured data directory");
+
+    let job_queue = Arc::new(
+        JobQueue::open(
+            config.data_directory.join("queue.json"),
+            config.job_queue_size,
+            config.supersede_running_jobs,
+        )
+        .expect("failed to open job queue"),
+    );
+    let history = Arc::new(
+        History::open(config.data_directory.join("history.jsonl"))
+            .expect("failed to open job history"),
+    );
+    let deliveries = Arc::new(
+        DeliveryLog::open(
+            config.data_directory.join("deliveries.json"),
+            config.delivery_log_size,
+        )
+        .expect("failed to open delivery log"),
+    );
+
+    let (report_sender, report_receiver) = crossbeam_channel::unbounded();
+    for slot in 0..config.workers {
+        let (log_directory, vm_providers, test, raw_url, job_queue, history, report_sender) = (
+            config.log_directory.clone(),
+            Arc::clone(&vm_providers),
+            config.test.clone(),
+            config.github.raw_url.clone(),
+            Arc::clone(&job_queue),
+            Arc::clone(&history),
+            report_sender.clone(),
+        );
+        // The OpenStack `Cloud` isn't `Send` so we have to initialize the `Worker` on its own
+        // thread
+        thread::spawn(move || {
+            let worker = Worker::new(
+                log_directory,
+                vm_providers(slot),
+                test,
+                raw_url,
+                job_queue,
+                history,
+                report_sender,
+            );
+
+            // TODO: Restart on panic
+            worker.run();
+        });
+    }
+
+    let sys = actix_rt::System::new("runtime");
+
+    let check_run_store = CheckRunStore::open(config.data_directory.join("check_runs.json"))
+        .expect("failed to open check run store");
+    let publisher = Publisher::new(
+        github.clone(),
+        config.public_url,
+        check_clients,
+        check_run_store,
+    );
+    actix_rt::spawn(
+        futures::stream::iter_ok(report_receiver).for_each(move |report| {
+            // Keep publishing reports even if one of them fails
+            publisher.handle_report(report).then(|_| Ok(()))
+        }),
+    );
+
+    let state = AppState {
+        config: config.github,
+        max_packets: config.test.max_packets,
+        github,
+        job_queue: Arc::clone(&job_queue),
+        history: Arc::clone(&history),
+        report_sender,
+        deliveries,
+    };
+    let (admin_config, log_directory) = (config.admin, config.log_directory);
+    HttpServer::new(move || {
+        App::new()
+            .data(state.clone())
+            .data(admin_config.clone())
+            .data(Arc::clone(&job_queue))
+            .data(Arc::clone(&history))
+            .wrap(Logger::default())
+            .service(Files::new("/logs/", &log_directory))
+            .service(dashboard::index)
+            .service(dashboard::job_history)
+            .service(web::scope("/badge/").service(badge::badge))
+            .service(web::scope("/github/").service(github::webhook_service))
+            .service(
+                web::scope("/api/")
+                    .service(api::list_jobs)
+                    .service(api::get_job)
+                    .service(api::cancel_job)
+                    .service(api::list_deliveries)
+                    .service(api::replay_delivery),
+            )
+    })
+    .bind(config.bind_address)?
+    .start();
+
+    sys.run()
+}
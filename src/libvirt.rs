@@ -0,0 +1,267 @@
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::process::Command;
+use std::time::Duration;
+
+use log::*;
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
+
+use crate::config::{LibvirtConfig, PciAddresses};
+use crate::utility;
+use crate::vm::VmAddresses;
+
+const VM_PKTGEN: &str = "pktgen";
+const VM_FWD: &str = "fwd";
+const VM_PCAP: &str = "pcap";
+
+const NETWORK_PKTGEN_FWD: &str = "pktgen-fwd";
+const NETWORK_FWD_PCAP: &str = "fwd-pcap";
+
+// Booting takes a while until the VM requests an address via DHCP
+const ADDRESS_RETRY_DELAY: Duration = Duration::from_secs(2);
+const ADDRESS_MAX_RETRIES: usize = 60;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Failed to execute {}: {}", program, source))]
+    Execute {
+        program: &'static str,
+        source: io::Error,
+    },
+    #[snafu(display("`{}` failed: {}", command, output))]
+    CommandFailed { command: String, output: String },
+    #[snafu(display("Failed to write VM definition or disk: {}", source))]
+    Io { source: io::Error },
+    #[snafu(display("VM {} didn't get an address", vm))]
+    MissingAddress { vm: String },
+}
+
+/// Spawns the VMs locally via libvirt/QEMU. The test networks are isolated bridges which connect
+/// the VirtIO NICs of the VMs; the VMs are accessed via SSH through the management network.
+pub struct Libvirt {
+    pub config: LibvirtConfig,
+    /// Prefix of all domains and networks created by this instance
+    prefix: String,
+    /// Short prefix for bridge names which are limited to 15 characters
+    bridge_prefix: String,
+    pci_addresses: PciAddresses,
+}
+
+impl Libvirt {
+    pub fn new(config: LibvirtConfig, slot: usize, pci_addresses: &PciAddresses) -> Libvirt {
+        Libvirt {
+            config,
+            prefix: format!("ixy-ci-{}-", slot),
+            bridge_prefix: format!("ixyci{}", slot),
+            pci_addresses: pci_addresses.clone(),
+        }
+    }
+
+    pub fn spawn_vms(&self) -> Result<VmAddresses, Error> {
+        self.clean_environment()?;
+
+        self.create_network(NETWORK_PKTGEN_FWD, "pf")?;
+        self.create_network(NETWORK_FWD_PCAP, "fp")?;
+
+        self.create_vm(
+            VM_PKTGEN,
+            &[(NETWORK_PKTGEN_FWD, &self.pci_addresses.pktgen)],
+        )?;
+        self.create_vm(
+            VM_FWD,
+            &[
+                (NETWORK_PKTGEN_FWD, &self.pci_addresses.fwd_src),
+                (NETWORK_FWD_PCAP, &self.pci_addresses.fwd_dst),
+            ],
+        )?;
+        self.create_vm(VM_PCAP, &[(NETWORK_FWD_PCAP, &self.pci_addresses.pcap)])?;
+
+        Ok(VmAddresses {
+            pktgen: self.wait_for_address(VM_PKTGEN)?,
+            fwd: self.wait_for_address(VM_FWD)?,
+            pcap: self.wait_for_address(VM_PCAP)?,
+        })
+    }
+
+    pub fn clean_environment(&self) -> Result<(), Error> {
+        for vm in &[VM_PKTGEN, VM_FWD, VM_PCAP] {
+            let name = self.name(vm);
+            info!("Deleting VM {}", name);
+            // Fails if the VM isn't running or doesn't exist which is fine
+            let _ = self.virsh(&["destroy", &name]);
+            let _ = self.virsh(&["undefine", &name]);
+            match fs::remove_file(self.disk_path(vm)) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                r => r.context(Io)?,
+            }
+        }
+        for network in &[NETWORK_PKTGEN_FWD, NETWORK_FWD_PCAP] {
+            let name = self.name(network);
+            info!("Deleting network {}", name);
+            let _ = self.virsh(&["net-destroy", &name]);
+            let _ = self.virsh(&["net-undefine", &name]);
+        }
+        Ok(())
+    }
+
+    fn name(&self, name: &str) -> String {
+        format!("{}{}", self.prefix, name)
+    }
+
+    fn disk_path(&self, vm: &str) -> PathBuf {
+        self.config
+            .image_directory
+            .join(format!("{}.qcow2", self.name(vm)))
+    }
+
+    fn create_network(&self, name: &str, bridge_suffix: &str) -> Result<(), Error> {
+        let name = self.name(name);
+        info!("Creating network {}", name);
+        // Without `<forward>` and `<ip>` the network is an isolated bridge without DHCP
+        let xml = format!(
+            "<network>\n\
+             \x20 <name>{}</name>\n\
+             \x20 <bridge name='{}{}' stp='off' delay='0'/>\n\
+             </network>\n",
+            name, self.bridge_prefix, bridge_suffix
+        );
+        self.define("net-define", &name, &xml)?;
+        self.virsh(&["net-start", &name]).map(|_| ())
+    }
+
+    fn create_vm(&self, vm: &str, test_networks: &[(&str, &String)]) -> Result<(), Error> {
+        let name = self.name(vm);
+        info!("Creating VM {}", name);
+
+        // Copy-on-write disk backed by the base image
+        let disk = self.disk_path(vm);
+        let output = Command::new("qemu-img")
+            .args(&["create", "-f", "qcow2", "-F", "qcow2", "-b"])
+            .arg(&self.config.base_image)
+            .arg(&disk)
+            .output()
+            .context(Execute {
+                program: "qemu-img",
+            })?;
+        ensure!(
+            output.status.success(),
+            CommandFailed {
+                command: "qemu-img create",
+                output: String::from_utf8_lossy(&output.stderr).into_owned(),
+            }
+        );
+
+        let mut interfaces = String::new();
+        for (network, pci_address) in test_networks {
+            interfaces += &format!(
+                "    <interface type='network'>\n\
+                 \x20     <source network='{}'/>\n\
+                 \x20     <model type='virtio'/>\n\
+                 \x20     {}\n\
+                 \x20   </interface>\n",
+                self.name(network),
+                format_pci_address(pci_address)
+            );
+        }
+        let xml = format!(
+            "<domain type='kvm'>\n\
+             \x20 <name>{}</name>\n\
+             \x20 <memory unit='MiB'>{}</memory>\n\
+             \x20 <vcpu>{}</vcpu>\n\
+             \x20 <os><type arch='x86_64'>hvm</type></os>\n\
+             \x20 <cpu mode='host-passthrough'/>\n\
+             \x20 <devices>\n\
+             \x20   <disk type='file' device='disk'>\n\
+             \x20     <driver name='qemu' type='qcow2'/>\n\
+             \x20     <source file='{}'/>\n\
+             \x20     <target dev='vda' bus='virtio'/>\n\
+             \x20   </disk>\n\
+             \x20   <interface type='network'>\n\
+             \x20     <source network='{}'/>\n\
+             \x20     <model type='virtio'/>\n\
+             \x20   </interface>\n\
+             {}\
+             \x20   <serial type='pty'/>\n\
+             \x20   <console type='pty'/>\n\
+             \x20 </devices>\n\
+             </domain>\n",
+            name,
+            self.config.memory_mib,
+            self.config.vcpus,
+            disk.display(),
+            self.config.management_network,
+            interfaces
+        );
+        self.define("define", &name, &xml)?;
+        self.virsh(&["start", &name]).map(|_| ())
+    }
+
+    /// Defines a domain/network from the given XML (`virsh` only accepts files).
+    fn define(&self, command: &str, name: &str, xml: &str) -> Result<(), Error> {
+        let path = self.config.image_directory.join(format!("{}.xml", name));
+        fs::write(&path, xml).context(Io)?;
+        let result = self.virsh(&[command, &path.to_string_lossy()]);
+        fs::remove_file(&path).context(Io)?;
+        result.map(|_| ())
+    }
+
+    fn wait_for_address(&self, vm: &str) -> Result<IpAddr, Error> {
+        let name = self.name(vm);
+        utility::retry(ADDRESS_MAX_RETRIES, ADDRESS_RETRY_DELAY, || {
+            let output = self.virsh(&["domifaddr", &name, "--source", "lease"])?;
+            // Output format:
+            //  Name       MAC address          Protocol     Address
+            // -------------------------------------------------------------------------------
+            //  vnet0      52:54:00:8c:3f:a1    ipv4         192.168.122.45/24
+            output
+                .lines()
+                .filter_map(|line| {
+                    let columns: Vec<_> = line.split_whitespace().collect();
+                    match columns.as_slice() {
+                        [_, _, "ipv4", address] => address.split('/').next()?.parse().ok(),
+                        _ => None,
+                    }
+                })
+                .next()
+                .context(MissingAddress { vm: name.clone() })
+        })
+    }
+
+    fn virsh(&self, args: &[&str]) -> Result<String, Error> {
+        let output = Command::new("virsh")
+            .arg("--connect")
+            .arg(&self.config.uri)
+            .args(args)
+            .output()
+            .context(Execute { program: "virsh" })?;
+        ensure!(
+            output.status.success(),
+            CommandFailed {
+                command: format!("virsh {}", args.join(" ")),
+                output: String::from_utf8_lossy(&output.stderr).into_owned(),
+            }
+        );
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+}
+
+/// Converts a PCI address like `0000:00:06.0` into libvirt's `<address>` element so that the test
+/// NICs show up at the addresses the applications are started with.
+fn format_pci_address(pci_address: &str) -> String {
+    let mut parts = pci_address.split(|c| c == ':' || c == '.');
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(domain), Some(bus), Some(slot), Some(function)) => format!(
+            "<address type='pci' domain='0x{}' bus='0x{}' slot='0x{}' function='0x{}'/>",
+            domain, bus, slot, function
+        ),
+        _ => {
+            warn!(
+                "Invalid PCI address {}; letting libvirt choose one",
+                pci_address
+            );
+            String::new()
+        }
+    }
+}
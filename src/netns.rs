@@ -0,0 +1,142 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::process::Command;
+
+use log::*;
+use snafu::{ensure, ResultExt, Snafu};
+
+use crate::config::NamespaceConfig;
+
+const NAMESPACES: [&str; 3] = ["pktgen", "fwd", "pcap"];
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Failed to execute {}: {}", program, source))]
+    Execute {
+        program: &'static str,
+        source: io::Error,
+    },
+    #[snafu(display("`{}` failed: {}", command, output))]
+    CommandFailed { command: String, output: String },
+    #[snafu(display("Failed to create or delete work directory: {}", source))]
+    Io { source: io::Error },
+}
+
+/// A network namespace together with the directory which acts as its home directory
+#[derive(Debug, Clone)]
+pub struct Namespace {
+    pub name: String,
+    pub directory: PathBuf,
+}
+
+/// Runs pktgen, fwd and pcap as processes in three network namespaces on the local host. The
+/// namespaces are connected by veth pairs (`pktgen/veth0 <-> fwd/veth0` and
+/// `fwd/veth1 <-> pcap/veth0`) so the applications have to support AF_PACKET/TAP instead of
+/// VirtIO. This requires ixy-ci to run as root.
+pub struct Namespaces {
+    pub config: NamespaceConfig,
+    /// Prefix of all namespaces created by this instance
+    prefix: String,
+    /// Short prefix for veth names which are limited to 15 characters
+    link_prefix: String,
+}
+
+impl Namespaces {
+    pub fn new(config: NamespaceConfig, slot: usize) -> Namespaces {
+        Namespaces {
+            config,
+            prefix: format!("ixy-ci-{}-", slot),
+            link_prefix: format!("ixyci{}", slot),
+        }
+    }
+
+    pub fn spawn_namespaces(&self) -> Result<(Namespace, Namespace, Namespace), Error> {
+        self.clean_environment()?;
+
+        for namespace in &NAMESPACES {
+            let namespace = self.namespace(namespace);
+            info!("Creating namespace {}", namespace.name);
+            ip(&["netns", "add", &namespace.name])?;
+            ip(&["-n", &namespace.name, "link", "set", "lo", "up"])?;
+            fs::create_dir_all(&namespace.directory).context(Io)?;
+        }
+
+        self.create_link("pf", ("pktgen", "veth0"), ("fwd", "veth0"))?;
+        self.create_link("fp", ("fwd", "veth1"), ("pcap", "veth0"))?;
+
+        Ok((
+            self.namespace("pktgen"),
+            self.namespace("fwd"),
+            self.namespace("pcap"),
+        ))
+    }
+
+    pub fn clean_environment(&self) -> Result<(), Error> {
+        for namespace in &NAMESPACES {
+            let namespace = self.namespace(namespace);
+            info!("Deleting namespace {}", namespace.name);
+            // Fails if the namespace doesn't exist which is fine; its veths are deleted with it
+            let _ = ip(&["netns", "delete", &namespace.name]);
+            match fs::remove_dir_all(&namespace.directory) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                r => r.context(Io)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn namespace(&self, name: &str) -> Namespace {
+        let name = format!("{}{}", self.prefix, name);
+        Namespace {
+            directory: self.config.work_directory.join(&name),
+            name,
+        }
+    }
+
+    /// Connects two namespaces with a veth pair whose ends are renamed to the given interfaces.
+    fn create_link(
+        &self,
+        suffix: &str,
+        (namespace_a, interface_a): (&str, &str),
+        (namespace_b, interface_b): (&str, &str),
+    ) -> Result<(), Error> {
+        let (link_a, link_b) = (
+            format!("{}{}0", self.link_prefix, suffix),
+            format!("{}{}1", self.link_prefix, suffix),
+        );
+        ip(&[
+            "link", "add", &link_a, "type", "veth", "peer", "name", &link_b,
+        ])?;
+        for (link, namespace, interface) in &[
+            (&link_a, namespace_a, interface_a),
+            (&link_b, namespace_b, interface_b),
+        ] {
+            let namespace = self.namespace(namespace).name;
+            ip(&["link", "set", link, "netns", &namespace])?;
+            ip(&["-n", &namespace, "link", "set", link, "name", interface])?;
+            // Otherwise the kernel sends neighbor discovery packets which end up in the capture
+            let _ = Command::new("ip")
+                .args(&["netns", "exec", &namespace, "sysctl", "-qw"])
+                .arg(format!("net.ipv6.conf.{}.disable_ipv6=1", interface))
+                .output();
+            ip(&["-n", &namespace, "link", "set", interface, "up"])?;
+        }
+        Ok(())
+    }
+}
+
+fn ip(args: &[&str]) -> Result<(), Error> {
+    let output = Command::new("ip")
+        .args(args)
+        .output()
+        .context(Execute { program: "ip" })?;
+    ensure!(
+        output.status.success(),
+        CommandFailed {
+            command: format!("ip {}", args.join(" ")),
+            output: String::from_utf8_lossy(&output.stderr).into_owned(),
+        }
+    );
+    Ok(())
+}
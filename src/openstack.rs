@@ -15,22 +15,39 @@ use waiter::Waiter;
 use crate::config::OpenStackConfig;
 use crate::utility;
 
-// Fixed VM names as we require a specific OpenStack setup anyways
 const VM_PKTGEN: &str = "pktgen";
 const VM_FWD: &str = "fwd";
 const VM_PCAP: &str = "pcap";
 const VM_VOLUME_SIZE_GB: u32 = 20;
 
+const NETWORK_PKTGEN_FWD: &str = "pktgen-fwd";
+const NETWORK_FWD_PCAP: &str = "fwd-pcap";
+// The internal networks don't need routing or DHCP as they're only used by ixy
+const INTERNAL_SUBNET_RANGE: &str = "10.0.0.0/24";
+
 const RETRY_DELAY: Duration = Duration::from_millis(500);
 const MAX_RETRIES: usize = 10;
+// Volumes stay "detaching" for a while after their server has been deleted
+const VOLUME_RETRY_DELAY: Duration = Duration::from_secs(2);
+const MAX_VOLUME_RETRIES: usize = 30;
+
+/// Deletes the resources of all worker slots and everything else that's unused.
+pub fn clean_all(config: &OpenStackConfig, slots: usize) -> Result<(), Error> {
+    for slot in 0..slots {
+        OpenStack::new(config.clone(), slot)?.clean_environment()?;
+    }
+    OpenStack::new(config.clone(), 0)?.clean_unused_resources()
+}
 
 pub struct OpenStack {
     pub config: OpenStackConfig,
     cloud: Cloud,
+    /// Prefix of all resources created by this instance so that multiple workers don't interfere
+    prefix: String,
 }
 
 impl OpenStack {
-    pub fn new(config: OpenStackConfig) -> Result<OpenStack, Error> {
+    pub fn new(config: OpenStackConfig, slot: usize) -> Result<OpenStack, Error> {
         let auth = Password::new(
             &config.auth_url,
             &config.user_name,
@@ -41,12 +58,20 @@ impl OpenStack {
         Ok(OpenStack {
             cloud: Cloud::new(auth),
             config,
+            prefix: format!("ixy-ci-{}-", slot),
         })
     }
 
     pub fn spawn_vms(&self) -> Result<(IpAddr, IpAddr, IpAddr), Error> {
         self.clean_environment()?;
 
+        self.create_network(NETWORK_PKTGEN_FWD)?;
+        self.create_network(NETWORK_FWD_PCAP)?;
+        self.create_port(NETWORK_PKTGEN_FWD, "pktgen")?;
+        self.create_port(NETWORK_PKTGEN_FWD, "fwd-in")?;
+        self.create_port(NETWORK_FWD_PCAP, "fwd-out")?;
+        self.create_port(NETWORK_FWD_PCAP, "pcap")?;
+
         let ip_pktgen = self.create_server(VM_PKTGEN)?;
         let ip_fwd = self.create_server(VM_FWD)?;
         let ip_pcap = self.create_server(VM_PCAP)?;
@@ -59,11 +84,24 @@ impl OpenStack {
         Ok((ip_pktgen, ip_fwd, ip_pcap))
     }
 
+    /// Deletes all resources belonging to this instance (also those left over from a crash).
     pub fn clean_environment(&self) -> Result<(), Error> {
-        self.delete_server(VM_PKTGEN);
-        self.delete_server(VM_FWD);
-        self.delete_server(VM_PCAP);
+        self.delete_server(VM_PKTGEN)?;
+        self.delete_server(VM_FWD)?;
+        self.delete_server(VM_PCAP)?;
+
+        for port in &["pktgen", "fwd-in", "fwd-out", "pcap"] {
+            self.delete_port(port);
+        }
+        self.delete_network(NETWORK_PKTGEN_FWD);
+        self.delete_network(NETWORK_FWD_PCAP);
 
+        Ok(())
+    }
+
+    /// Deletes volumes and floating ips which aren't used by any server. Must not be called while
+    /// VMs are being spawned as their resources may not be attached yet.
+    fn clean_unused_resources(&self) -> Result<(), Error> {
         info!("Deleting unused volumes");
         for v in self.get_unused_volumes()? {
             self.delete_volume(&v)?;
@@ -80,8 +118,70 @@ impl OpenStack {
             })
     }
 
+    fn name(&self, name: &str) -> String {
+        format!("{}{}", self.prefix, name)
+    }
+
+    fn create_network(&self, name: &str) -> Result<(), Error> {
+        info!("Creating network {}", self.name(name));
+        self.wrap_openstack_cli(&["network", "create", &self.name(name)], |_| Ok(()))?;
+        // A subnet is required for port creation to succeed
+        self.wrap_openstack_cli(
+            &[
+                "subnet",
+                "create",
+                "--network",
+                &self.name(name),
+                "--subnet-range",
+                INTERNAL_SUBNET_RANGE,
+                "--gateway",
+                "none",
+                "--no-dhcp",
+                &self.name(name),
+            ],
+            |_| Ok(()),
+        )
+    }
+
+    fn delete_network(&self, name: &str) {
+        // Also deletes the network's subnet
+        if self
+            .wrap_openstack_cli(&["network", "delete", &self.name(name)], |_| Ok(()))
+            .is_err()
+        {
+            info!("Network doesn't exist or failed to delete");
+        }
+    }
+
+    fn create_port(&self, network: &str, name: &str) -> Result<(), Error> {
+        info!("Creating port {}", self.name(name));
+        // Port security (anti-spoofing) must be disabled as the packets generated by pktgen
+        // don't match the port's addresses
+        self.wrap_openstack_cli(
+            &[
+                "port",
+                "create",
+                "--network",
+                &self.name(network),
+                "--disable-port-security",
+                &self.name(name),
+            ],
+            |_| Ok(()),
+        )
+    }
+
+    fn delete_port(&self, name: &str) {
+        if self
+            .wrap_openstack_cli(&["port", "delete", &self.name(name)], |_| Ok(()))
+            .is_err()
+        {
+            info!("Port doesn't exist or failed to delete");
+        }
+    }
+
     fn create_server(&self, name: &str) -> Result<IpAddr, Error> {
-        info!("Creating server");
+        let name = &self.name(name);
+        info!("Creating server {}", name);
         // Port for the internal network must be added later due to some reason I don't understand.
         // We also can't just connect to the network and use an auto-generated port as we need to
         // disable port security (anti-spoofing) which isn't supported yet by the openstack crate.
@@ -110,19 +210,57 @@ impl OpenStack {
         })
     }
 
-    fn delete_server(&self, name: &str) {
-        info!("Deleting server");
+    /// Deletes the server together with its volumes and floating ip.
+    fn delete_server(&self, name: &str) -> Result<(), Error> {
+        let name = &self.name(name);
+        info!("Deleting server {}", name);
         match self.cloud.get_server(name) {
             Ok(server) => {
-                server
-                    .delete()
-                    .expect("can't delete server")
-                    .wait()
-                    .expect("failed to delete server");
+                // We can't use the global cleanup of unused resources as that would race with other
+                // workers so we have to collect the server's resources before deleting it
+                let volumes = self.get_server_volumes(name)?;
+                let floating_ip = server.floating_ip();
+
+                server.delete()?.wait()?;
+
+                if let Some(floating_ip) = floating_ip {
+                    info!("Deleting floating ip {}", floating_ip);
+                    self.wrap_openstack_cli(
+                        &["floating", "ip", "delete", &floating_ip.to_string()],
+                        |_| Ok(()),
+                    )?;
+                }
+                for v in volumes {
+                    self.delete_volume(&v)?;
+                }
             }
             // TODO: missing != error
             _ => info!("Server doesn't exist or failed to query"),
         }
+        Ok(())
+    }
+
+    fn get_server_volumes(&self, server: &str) -> Result<Vec<String>, Error> {
+        self.wrap_openstack_cli(
+            &[
+                "server",
+                "show",
+                server,
+                "-f",
+                "value",
+                "-c",
+                "volumes_attached",
+            ],
+            |output| {
+                // The format of this field differs between versions of the CLI (`id='<id>'` vs.
+                // JSON) so we just extract everything that looks like an id
+                Ok(String::from_utf8_lossy(&output.stdout)
+                    .split(|c: char| !(c.is_ascii_hexdigit() || c == '-'))
+                    .filter(|s| s.len() == 36 && s.matches('-').count() == 4)
+                    .map(|s| s.to_string())
+                    .collect())
+            },
+        )
     }
 
     fn get_unused_volumes(&self) -> Result<Vec<String>, Error> {
@@ -150,8 +288,26 @@ impl OpenStack {
         )
     }
 
+    /// Deletes the volume once it has been detached from its (deleted) server.
     fn delete_volume(&self, id: &str) -> Result<(), Error> {
-        self.wrap_openstack_cli(&["volume", "delete", id], |_| Ok(()))
+        info!("Deleting volume {}", id);
+        utility::retry(MAX_VOLUME_RETRIES, VOLUME_RETRY_DELAY, || {
+            let status = self.get_volume_status(id)?;
+            if status != "available" {
+                return Err(Error::new(
+                    ErrorKind::OperationTimedOut,
+                    format!("volume {} is still {}", id, status),
+                ));
+            }
+            self.wrap_openstack_cli(&["volume", "delete", id], |_| Ok(()))
+        })
+    }
+
+    fn get_volume_status(&self, id: &str) -> Result<String, Error> {
+        self.wrap_openstack_cli(
+            &["volume", "show", id, "-f", "value", "-c", "status"],
+            |output| Ok(String::from_utf8_lossy(&output.stdout).trim().to_string()),
+        )
     }
 
     fn add_port_to_vm(&self, server: &str, port: &str) -> Result<(), Error> {
@@ -163,7 +319,16 @@ impl OpenStack {
         //     .one()?;
         // port.with_device_id(server.id()).with_device_owner("compute:nova").with_admin_state_up(true).save().?;
 
-        self.wrap_openstack_cli(&["server", "add", "port", server, port], |_| Ok(()))
+        self.wrap_openstack_cli(
+            &[
+                "server",
+                "add",
+                "port",
+                &self.name(server),
+                &self.name(port),
+            ],
+            |_| Ok(()),
+        )
     }
 
     // TODO: Replace usages of the OpenStack CLI once the openstack crate supports everything we need
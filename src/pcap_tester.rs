@@ -1,108 +1,1077 @@
-use byteorder::{ByteOrder, LittleEndian};
-use etherparse::{ReadError, SlicedPacket, TransportSlice};
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
+use std::ops::Range;
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use etherparse::{SlicedPacket, TransportSlice};
 use log::*;
 use pcap_file::pcap::PcapReader;
 use pcap_file::PcapError;
 use snafu::{ensure, ResultExt, Snafu};
 
+use crate::config::{Endianness, PacketFormat, PcapCriteria, Protocol};
+
+/// How many malformed packets and gaps are listed in reports
+const MAX_SAMPLES: usize = 5;
+
+/// Offset of the transport header in the frame
+const TRANSPORT_OFFSET: usize = 34;
+
 #[derive(Debug, Snafu)]
 pub enum Error {
     #[snafu(display("Failed to parse pcap file: {}", source))]
     Pcap { source: PcapError },
+    #[snafu(display("Invalid packet format: {}", reason))]
+    InvalidFormat { reason: &'static str },
     #[snafu(display(
-        "Failed to parse ethernet frame: {:?}; packet: {:x?}",
-        read_error,
-        packet
+        "The capture didn't pass the checks:\n{}\n{}",
+        FailureList(failures),
+        report
     ))]
-    EtherParse {
-        read_error: ReadError,
-        packet: Vec<u8>,
+    Failed {
+        report: PcapReport,
+        failures: Vec<Failure>,
     },
-    #[snafu(display(
-        "Malformed UDP packet (invalid length or missing \"ixy\"): {:x?}",
-        packet
-    ))]
-    MalformedUdpPacket { packet: Vec<u8> },
+}
+
+/// A check of the capture which didn't pass
+#[derive(Debug, Clone, PartialEq, Snafu)]
+pub enum Failure {
     #[snafu(display("Incorrect packet count: expected: {} actual: {}", expected, actual))]
     IncorrectPacketCount { expected: usize, actual: usize },
     #[snafu(display(
         "Bad sequence number: expected {} packets but max sequence number was {}",
         packets,
-        max_seq_num
+        max_seq_num.map(|n| n.to_string()).unwrap_or_else(|| "missing".to_string())
     ))]
-    BadSequenceNumber { packets: usize, max_seq_num: u32 },
-    #[snafu(display("Some sequence number occured more than once"))]
-    DuplicateSequenceNumber,
-    // #[snafu(display("Wrong packet order: last sequence number was {} and now encountered {}", last_seq_num, seq_num))]
-    // InvalidSequenceOrder {
-    //     last_seq_num: u32,
-    //     seq_num: u32,
-    // },
-}
-
-pub fn test_pcap(pcap: &[u8], pcap_n: usize) -> Result<(), Error> {
-    // TODO: Check that no packets are duplicated
-    let pcap_reader = PcapReader::new(pcap).context(Pcap)?;
+    BadSequenceNumber {
+        packets: usize,
+        max_seq_num: Option<u32>,
+    },
+    #[snafu(display("{} malformed packets (at most {} allowed)", count, max))]
+    TooManyMalformedPackets { count: usize, max: usize },
+    #[snafu(display("{} duplicate packets (at most {} allowed)", count, max))]
+    TooManyDuplicates { count: usize, max: usize },
+    #[snafu(display("{} inversions in the packet order (at most {} allowed)", count, max))]
+    TooManyInversions { count: u64, max: u64 },
+    #[snafu(display(
+        "A packet arrived {} positions away from its place in the sequence (at most {} allowed)",
+        displacement,
+        max
+    ))]
+    DisplacementTooLarge { displacement: usize, max: usize },
+    #[snafu(display(
+        "{} packets ({:.2}%) were lost (at most {:.2}% allowed)",
+        missing,
+        ratio * 100.0,
+        max * 100.0
+    ))]
+    TooMuchLoss { missing: u64, ratio: f64, max: f64 },
+}
 
-    let mut count = 0;
-    // let mut last_seq_num = None;
-    let mut max_seq_num = 0;
-    let mut seq_nums = Vec::new();
-    for pcap in pcap_reader {
-        let pcap = pcap.context(Pcap)?;
-        let packet = SlicedPacket::from_ethernet(&pcap.data).map_err(|e| Error::EtherParse {
-            read_error: e,
-            packet: pcap.data.to_vec(),
-        })?;
-
-        if let Some(TransportSlice::Udp(udp_header)) = packet.transport {
-            if udp_header.length() != 26 || packet.payload[..3] != *b"ixy" {
-                return Err(Error::MalformedUdpPacket {
-                    packet: pcap.data.to_vec(),
-                });
-            }
-            let len = packet.payload.len();
-            let seq_num = LittleEndian::read_u32(&packet.payload[(len - 4)..]);
-            max_seq_num = max_seq_num.max(seq_num);
-            seq_nums.push(seq_num);
-            // Currently disabled as there's some kind of packet reordering happening on OpenStack
-            // Using the local libvirt/qemu setup no packets are reordered
-            // Remove redundant duplicate packet check again after reenabling this
-            // if let Some(last_seq_num) = last_seq_num {
-            //     if seq_num <= last_seq_num {
-            //         return Err(Error::InvalidSequenceOrder {
-            //             last_seq_num,
-            //             seq_num,
-            //         });
-            //     }
-            // }
-            // last_seq_num = Some(seq_num);
-            count += 1;
-        } else {
-            debug!("ignoring non-UDP packet")
+struct FailureList<'a>(&'a [Failure]);
+
+impl Display for FailureList<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for failure in self.0 {
+            writeln!(f, "- {}", failure)?;
         }
+        Ok(())
     }
+}
+
+/// A packet which isn't a valid pktgen packet
+#[derive(Debug, Clone)]
+pub struct MalformedPacket {
+    /// Position of the packet in the capture (starting at 0)
+    pub index: usize,
+    pub reason: String,
+    pub data: Vec<u8>,
+}
+
+/// A way in which a captured frame differs from the frame pktgen sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Mismatch {
+    /// The frame is shorter than the configured length (trailing bytes such as the FCS which some
+    /// NICs append are ignored)
+    FrameLength,
+    DstMac,
+    SrcMac,
+    EtherType,
+    /// Version, IHL, TOS, identification, flags, fragment offset or protocol
+    IpHeader,
+    IpLength,
+    Ttl,
+    IpChecksum,
+    SrcIp,
+    DstIp,
+    SrcPort,
+    DstPort,
+    UdpLength,
+    TransportChecksum,
+    Marker,
+    /// The zeroes between the marker and the sequence number
+    Padding,
+}
 
-    // Check that packet count is correct and that we didn't drop too many packets
+impl Display for Mismatch {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let description = match self {
+            Mismatch::FrameLength => "frame too short",
+            Mismatch::DstMac => "wrong destination MAC",
+            Mismatch::SrcMac => "wrong source MAC",
+            Mismatch::EtherType => "wrong EtherType",
+            Mismatch::IpHeader => "wrong IPv4 header",
+            Mismatch::IpLength => "wrong IPv4 total length",
+            Mismatch::Ttl => "wrong TTL",
+            Mismatch::IpChecksum => "invalid IPv4 checksum",
+            Mismatch::SrcIp => "wrong source IP",
+            Mismatch::DstIp => "wrong destination IP",
+            Mismatch::SrcPort => "wrong source port",
+            Mismatch::DstPort => "wrong destination port",
+            Mismatch::UdpLength => "wrong UDP length",
+            Mismatch::TransportChecksum => "invalid UDP/TCP checksum",
+            Mismatch::Marker => "missing marker",
+            Mismatch::Padding => "non-zero padding",
+        };
+        write!(f, "{}", description)
+    }
+}
+
+/// Header fields which have to match the frame pktgen sent (the checksums are verified instead as a
+/// forwarder may recalculate them)
+const HEADER_FIELDS: &[(Range<usize>, Mismatch)] = &[
+    (0..6, Mismatch::DstMac),
+    (6..12, Mismatch::SrcMac),
+    (12..14, Mismatch::EtherType),
+    (14..16, Mismatch::IpHeader),
+    (16..18, Mismatch::IpLength),
+    (18..22, Mismatch::IpHeader),
+    (22..23, Mismatch::Ttl),
+    (23..24, Mismatch::IpHeader),
+    (26..30, Mismatch::SrcIp),
+    (30..34, Mismatch::DstIp),
+    (34..36, Mismatch::SrcPort),
+    (36..38, Mismatch::DstPort),
+];
+
+fn transport_header_len(protocol: Protocol) -> usize {
+    match protocol {
+        Protocol::Udp => 8,
+        Protocol::Tcp => 20,
+    }
+}
+
+fn ip_protocol(protocol: Protocol) -> u8 {
+    match protocol {
+        Protocol::Udp => 0x11,
+        Protocol::Tcp => 0x06,
+    }
+}
+
+/// Offset of the UDP/TCP checksum in the frame
+fn checksum_offset(protocol: Protocol) -> usize {
+    match protocol {
+        Protocol::Udp => TRANSPORT_OFFSET + 6,
+        Protocol::Tcp => TRANSPORT_OFFSET + 16,
+    }
+}
+
+fn payload_offset(format: &PacketFormat) -> usize {
+    TRANSPORT_OFFSET + transport_header_len(format.protocol)
+}
+
+fn marker_range(format: &PacketFormat) -> Range<usize> {
+    let offset = payload_offset(format);
+    offset..(offset + format.marker.len())
+}
+
+fn seq_num_range(format: &PacketFormat) -> Range<usize> {
+    format.seq_offset..(format.seq_offset + 4)
+}
+
+/// Makes sure that the marker and the sequence number fit into the payload without overlapping.
+fn check_format(format: &PacketFormat) -> Result<(), Error> {
+    let marker = marker_range(format);
+    let seq_num = seq_num_range(format);
+    // Shorter frames would be padded to the minimum Ethernet frame size on the wire
+    ensure!(
+        format.length >= 60,
+        InvalidFormat {
+            reason: "frames can't be shorter than 60 bytes",
+        }
+    );
+    ensure!(
+        format.length <= 1514,
+        InvalidFormat {
+            reason: "frames can't be longer than 1514 bytes",
+        }
+    );
+    ensure!(
+        marker.end <= format.length,
+        InvalidFormat {
+            reason: "the marker doesn't fit into the frame",
+        }
+    );
     ensure!(
-        count == pcap_n,
-        IncorrectPacketCount {
-            expected: pcap_n,
-            actual: count
+        seq_num.start >= payload_offset(format) && seq_num.end <= format.length,
+        InvalidFormat {
+            reason: "the sequence number isn't within the payload",
         }
     );
     ensure!(
-        max_seq_num as usize >= pcap_n - 1 && max_seq_num as usize <= pcap_n * 2,
-        BadSequenceNumber {
-            packets: pcap_n,
-            max_seq_num,
+        seq_num.end <= marker.start || seq_num.start >= marker.end,
+        InvalidFormat {
+            reason: "the sequence number overlaps the marker",
         }
     );
+    Ok(())
+}
+
+fn read_seq_num(frame: &[u8], format: &PacketFormat) -> u32 {
+    let bytes = &frame[seq_num_range(format)];
+    match format.seq_endianness {
+        Endianness::Little => LittleEndian::read_u32(bytes),
+        Endianness::Big => BigEndian::read_u32(bytes),
+    }
+}
+
+/// Compares `frame` with the frame pktgen sends (apart from the sequence number). Bytes beyond the
+/// configured length are ignored.
+fn validate_frame(frame: &[u8], format: &PacketFormat) -> Vec<Mismatch> {
+    if frame.len() < format.length {
+        return vec![Mismatch::FrameLength];
+    }
+    let frame = &frame[..format.length];
+    let mut mismatches = Vec::new();
+
+    let expected = build_frame(format, 0);
+    let mut fields = HEADER_FIELDS.to_vec();
+    if format.protocol == Protocol::Udp {
+        fields.push((38..40, Mismatch::UdpLength));
+    }
+    fields.push((marker_range(format), Mismatch::Marker));
+    for (range, mismatch) in fields {
+        if frame[range.clone()] != expected[range] && !mismatches.contains(&mismatch) {
+            mismatches.push(mismatch);
+        }
+    }
+    let marker = marker_range(format);
+    let seq_num = seq_num_range(format);
+    if (payload_offset(format)..format.length)
+        .any(|i| frame[i] != 0 && !marker.contains(&i) && !seq_num.contains(&i))
+    {
+        mismatches.push(Mismatch::Padding);
+    }
+
+    if internet_checksum(&frame[14..TRANSPORT_OFFSET]) != 0 {
+        mismatches.push(Mismatch::IpChecksum);
+    }
+    let checksum = checksum_offset(format.protocol);
+    // The UDP checksum is optional (ixy's pktgen doesn't set it)
+    if (format.protocol == Protocol::Tcp || frame[checksum..(checksum + 2)] != [0, 0])
+        && internet_checksum(&transport_checksum_data(frame)) != 0
+    {
+        mismatches.push(Mismatch::TransportChecksum);
+    }
+    mismatches.sort();
+    mismatches
+}
+
+/// Returns the data over which the UDP/TCP checksum is calculated: a pseudo header (source IP,
+/// destination IP, protocol, length) followed by the transport header and the payload.
+fn transport_checksum_data(frame: &[u8]) -> Vec<u8> {
+    let ip_len = usize::from(u16::from_be_bytes([frame[16], frame[17]]));
+    let end = (14 + ip_len).min(frame.len()).max(TRANSPORT_OFFSET);
+    let len = (end - TRANSPORT_OFFSET) as u16;
+    let mut data = frame[26..TRANSPORT_OFFSET].to_vec();
+    data.extend_from_slice(&[0, frame[23]]);
+    data.extend_from_slice(&len.to_be_bytes());
+    data.extend_from_slice(&frame[TRANSPORT_OFFSET..end]);
+    data
+}
+
+/// Calculates the internet checksum (RFC 1071) of `data`. The result is 0 if `data` includes a
+/// valid checksum.
+fn internet_checksum(data: &[u8]) -> u16 {
+    let mut sum = data
+        .chunks(2)
+        .map(|word| u32::from(u16::from_be_bytes([word[0], *word.get(1).unwrap_or(&0)])))
+        .sum::<u32>();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// An inclusive range of sequence numbers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SeqRange {
+    pub start: u32,
+    pub end: u32,
+}
+
+impl SeqRange {
+    /// Returns how many sequence numbers the range contains.
+    pub fn count(&self) -> u64 {
+        u64::from(self.end - self.start) + 1
+    }
+}
+
+impl Display for SeqRange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.start == self.end {
+            write!(f, "{}", self.start)
+        } else {
+            write!(f, "{}-{}", self.start, self.end)
+        }
+    }
+}
+
+/// How much the order of the captured packets deviates from the order in which they were sent
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReorderStats {
+    /// Packets whose sequence number is lower than the one of a packet captured before
+    pub out_of_order: usize,
+    /// Pairs of packets which were captured in the wrong order
+    pub inversions: u64,
+    /// Largest distance between the position at which a packet was captured and its position in
+    /// the sorted sequence
+    pub max_displacement: usize,
+    /// Most packets which were captured in order without interruption
+    pub longest_in_order_run: usize,
+}
+
+impl ReorderStats {
+    /// Calculates the statistics of the sequence numbers in the order in which they were captured.
+    pub fn calculate(seq_nums: &[u32]) -> ReorderStats {
+        let mut stats = ReorderStats::default();
+        let mut run = 0;
+        for (i, seq_num) in seq_nums.iter().enumerate() {
+            if i > 0 && *seq_num <= seq_nums[i - 1] {
+                run = 0;
+            }
+            run += 1;
+            stats.longest_in_order_run = stats.longest_in_order_run.max(run);
+        }
+
+        let mut max_seq_num = None;
+        for seq_num in seq_nums {
+            if Some(*seq_num) < max_seq_num {
+                stats.out_of_order += 1;
+            }
+            max_seq_num = max_seq_num.max(Some(*seq_num));
+        }
+
+        // The sort is stable so duplicates keep their order and aren't counted as displaced
+        let mut sorted: Vec<_> = (0..seq_nums.len()).collect();
+        sorted.sort_by_key(|&i| seq_nums[i]);
+        stats.max_displacement = sorted
+            .iter()
+            .enumerate()
+            .map(|(position, &i)| {
+                if position > i {
+                    position - i
+                } else {
+                    i - position
+                }
+            })
+            .max()
+            .unwrap_or(0);
+
+        stats.inversions = count_inversions(&mut seq_nums.to_vec());
+        stats
+    }
+}
+
+/// Counts the pairs `i < j` with `seq_nums[i] > seq_nums[j]` via merge sort (sorting `seq_nums`).
+fn count_inversions(seq_nums: &mut [u32]) -> u64 {
+    if seq_nums.len() < 2 {
+        return 0;
+    }
+    let mid = seq_nums.len() / 2;
+    let mut inversions =
+        count_inversions(&mut seq_nums[..mid]) + count_inversions(&mut seq_nums[mid..]);
+
+    let mut merged = Vec::with_capacity(seq_nums.len());
+    let (left, right) = seq_nums.split_at(mid);
+    let (mut i, mut j) = (0, 0);
+    while i < left.len() && j < right.len() {
+        if left[i] <= right[j] {
+            merged.push(left[i]);
+            i += 1;
+        } else {
+            // All remaining elements of the left half are greater than this one
+            inversions += (left.len() - i) as u64;
+            merged.push(right[j]);
+            j += 1;
+        }
+    }
+    merged.extend_from_slice(&left[i..]);
+    merged.extend_from_slice(&right[j..]);
+    seq_nums.copy_from_slice(&merged);
+    inversions
+}
+
+/// Everything we found out about a capture
+#[derive(Debug, Clone, Default)]
+pub struct PcapReport {
+    pub total_packets: usize,
+    /// Packets of the configured protocol
+    pub test_packets: usize,
+    pub other_packets: usize,
+    pub malformed_packets: usize,
+    /// The first few malformed packets
+    pub malformed_samples: Vec<MalformedPacket>,
+    /// How many packets differ from the frame pktgen sent in which way
+    pub mismatches: BTreeMap<Mismatch, usize>,
+    /// Packets whose sequence number was already captured before
+    pub duplicates: usize,
+    /// Sequence numbers which weren't captured (between the lowest and the highest captured one)
+    /// in ascending order
+    pub missing: Vec<SeqRange>,
+    pub reordering: ReorderStats,
+    pub min_seq_num: Option<u32>,
+    pub max_seq_num: Option<u32>,
+}
 
-    let pre_dedup = seq_nums.len();
+impl PcapReport {
+    /// Returns all checks which the capture doesn't pass.
+    pub fn evaluate(&self, pcap_n: usize, criteria: &PcapCriteria) -> Vec<Failure> {
+        let mut failures = Vec::new();
+        // Check that packet count is correct and that we didn't drop too many packets
+        if self.test_packets != pcap_n {
+            failures.push(Failure::IncorrectPacketCount {
+                expected: pcap_n,
+                actual: self.test_packets,
+            });
+        }
+        let seq_num_ok = match self.max_seq_num {
+            Some(max_seq_num) => {
+                max_seq_num as usize >= pcap_n.saturating_sub(1)
+                    && max_seq_num as usize <= pcap_n.saturating_mul(2)
+            }
+            None => false,
+        };
+        if !seq_num_ok {
+            failures.push(Failure::BadSequenceNumber {
+                packets: pcap_n,
+                max_seq_num: self.max_seq_num,
+            });
+        }
+        if self.malformed_packets > criteria.max_malformed {
+            failures.push(Failure::TooManyMalformedPackets {
+                count: self.malformed_packets,
+                max: criteria.max_malformed,
+            });
+        }
+        if self.duplicates > criteria.max_duplicates {
+            failures.push(Failure::TooManyDuplicates {
+                count: self.duplicates,
+                max: criteria.max_duplicates,
+            });
+        }
+        // Some kind of packet reordering happens on OpenStack (but not with the local
+        // libvirt/qemu setup) so reordering is only checked if a tolerance is configured
+        if let Some(max) = criteria.max_inversions {
+            if self.reordering.inversions > max {
+                failures.push(Failure::TooManyInversions {
+                    count: self.reordering.inversions,
+                    max,
+                });
+            }
+        }
+        if let Some(max) = criteria.max_displacement {
+            if self.reordering.max_displacement > max {
+                failures.push(Failure::DisplacementTooLarge {
+                    displacement: self.reordering.max_displacement,
+                    max,
+                });
+            }
+        }
+        if let Some(max) = criteria.max_loss_ratio {
+            let ratio = self.loss_ratio();
+            if ratio > max {
+                failures.push(Failure::TooMuchLoss {
+                    missing: self.missing_count(),
+                    ratio,
+                    max,
+                });
+            }
+        }
+        failures
+    }
+
+    /// Returns how many sequence numbers between the lowest and the highest captured one are
+    /// missing.
+    pub fn missing_count(&self) -> u64 {
+        self.missing.iter().map(SeqRange::count).sum()
+    }
+
+    /// Returns the share of sequence numbers between the lowest and the highest captured one which
+    /// are missing.
+    pub fn loss_ratio(&self) -> f64 {
+        match (self.min_seq_num, self.max_seq_num) {
+            (Some(min), Some(max)) => {
+                self.missing_count() as f64 / (u64::from(max - min) + 1) as f64
+            }
+            _ => 0.0,
+        }
+    }
+
+    /// Returns the `n` largest gaps (largest first).
+    pub fn biggest_gaps(&self, n: usize) -> Vec<SeqRange> {
+        let mut gaps = self.missing.clone();
+        gaps.sort_by_key(|gap| (std::cmp::Reverse(gap.count()), gap.start));
+        gaps.truncate(n);
+        gaps
+    }
+
+    fn add_malformed(&mut self, index: usize, reason: String, data: &[u8]) {
+        self.malformed_packets += 1;
+        if self.malformed_samples.len() < MAX_SAMPLES {
+            self.malformed_samples.push(MalformedPacket {
+                index,
+                reason,
+                data: data.to_vec(),
+            });
+        }
+    }
+}
+
+impl Display for PcapReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Captured packets: {} ({} test packets, {} other)",
+            self.total_packets, self.test_packets, self.other_packets
+        )?;
+        writeln!(f, "Malformed packets: {}", self.malformed_packets)?;
+        if !self.mismatches.is_empty() {
+            let mismatches: Vec<_> = self
+                .mismatches
+                .iter()
+                .map(|(mismatch, count)| format!("{}: {}", mismatch, count))
+                .collect();
+            writeln!(f, "Mismatches: {}", mismatches.join(", "))?;
+        }
+        for sample in &self.malformed_samples {
+            writeln!(
+                f,
+                "- packet {}: {}: {:x?}",
+                sample.index, sample.reason, sample.data
+            )?;
+        }
+        writeln!(f, "Duplicate packets: {}", self.duplicates)?;
+        writeln!(
+            f,
+            "Reordering: {} out-of-order packets, {} inversions, max displacement {}, \
+             longest in-order run {}",
+            self.reordering.out_of_order,
+            self.reordering.inversions,
+            self.reordering.max_displacement,
+            self.reordering.longest_in_order_run
+        )?;
+        writeln!(
+            f,
+            "Missing sequence numbers: {} ({:.2}%) in {} gaps",
+            self.missing_count(),
+            self.loss_ratio() * 100.0,
+            self.missing.len()
+        )?;
+        if !self.missing.is_empty() {
+            let gaps: Vec<_> = self
+                .biggest_gaps(MAX_SAMPLES)
+                .iter()
+                .map(|gap| format!("{} ({})", gap, gap.count()))
+                .collect();
+            writeln!(f, "Biggest gaps: {}", gaps.join(", "))?;
+        }
+        match (self.min_seq_num, self.max_seq_num) {
+            (Some(min_seq_num), Some(max_seq_num)) => {
+                writeln!(f, "Sequence numbers: {} to {}", min_seq_num, max_seq_num)
+            }
+            _ => writeln!(f, "Sequence numbers: none"),
+        }
+    }
+}
+
+/// Analyzes the capture and checks it against `criteria`.
+pub fn test_pcap(
+    pcap: &[u8],
+    pcap_n: usize,
+    format: &PacketFormat,
+    criteria: &PcapCriteria,
+) -> Result<PcapReport, Error> {
+    let report = analyze_pcap(pcap, format)?;
+    let failures = report.evaluate(pcap_n, criteria);
+    if failures.is_empty() {
+        Ok(report)
+    } else {
+        Err(Error::Failed { report, failures })
+    }
+}
+
+/// Collects statistics about all packets of the capture.
+pub fn analyze_pcap(pcap: &[u8], format: &PacketFormat) -> Result<PcapReport, Error> {
+    check_format(format)?;
+    let pcap_reader = PcapReader::new(pcap).context(Pcap)?;
+
+    let mut report = PcapReport::default();
+    let mut seq_nums = Vec::new();
+    for (index, pcap) in pcap_reader.enumerate() {
+        let pcap = pcap.context(Pcap)?;
+        report.total_packets += 1;
+
+        let packet = match SlicedPacket::from_ethernet(&pcap.data) {
+            Ok(packet) => packet,
+            Err(e) => {
+                let reason = format!("failed to parse ethernet frame ({:?})", e);
+                report.add_malformed(index, reason, &pcap.data);
+                continue;
+            }
+        };
+        let protocol = match packet.transport {
+            Some(TransportSlice::Udp(_)) => Some(Protocol::Udp),
+            Some(TransportSlice::Tcp(_)) => Some(Protocol::Tcp),
+            _ => None,
+        };
+        if protocol == Some(format.protocol) {
+            report.test_packets += 1;
+            let mismatches = validate_frame(&pcap.data, format);
+            if !mismatches.is_empty() {
+                for mismatch in &mismatches {
+                    *report.mismatches.entry(*mismatch).or_insert(0) += 1;
+                }
+                let reasons: Vec<_> = mismatches.iter().map(Mismatch::to_string).collect();
+                report.add_malformed(index, reasons.join(", "), &pcap.data);
+                continue;
+            }
+            let seq_num = read_seq_num(&pcap.data, format);
+            report.max_seq_num = report.max_seq_num.max(Some(seq_num));
+            seq_nums.push(seq_num);
+        } else {
+            debug!("ignoring packet which isn't {:?}", format.protocol);
+            report.other_packets += 1;
+        }
+    }
+
+    report.reordering = ReorderStats::calculate(&seq_nums);
     seq_nums.sort_unstable();
+    let captured = seq_nums.len();
     seq_nums.dedup();
-    ensure!(seq_nums.len() == pre_dedup, DuplicateSequenceNumber);
+    report.duplicates = captured - seq_nums.len();
+    report.min_seq_num = seq_nums.first().copied();
+    report.missing = seq_nums
+        .windows(2)
+        .filter(|pair| pair[1] - pair[0] > 1)
+        .map(|pair| SeqRange {
+            start: pair[0] + 1,
+            end: pair[1] - 1,
+        })
+        .collect();
 
-    Ok(())
+    Ok(report)
+}
+
+/// Builds the frame pktgen sends for the given sequence number (which is also what captured
+/// frames are validated against).
+pub(crate) fn build_frame(format: &PacketFormat, seq_num: u32) -> Vec<u8> {
+    let ip_len = (format.length - 14) as u16;
+    let mut frame = vec![
+        0x01, 0x02, 0x03, 0x04, 0x05, 0x06, // dst MAC
+        0x10, 0x10, 0x10, 0x10, 0x10, 0x10, // src MAC
+        0x08, 0x00, // ether type: IPv4
+        0x45, 0x00, // version, IHL, TOS
+    ];
+    frame.extend_from_slice(&ip_len.to_be_bytes()); // total length
+    frame.extend_from_slice(&[
+        0x00,
+        0x00,
+        0x00,
+        0x00, // id, flags, fragment offset
+        0x40,
+        ip_protocol(format.protocol), // TTL: 64, protocol
+        0x00,
+        0x00, // checksum (calculated below)
+        0x0a,
+        0x00,
+        0x00,
+        0x01, // src IP: 10.0.0.1
+        0x0a,
+        0x00,
+        0x00,
+        0x02, // dst IP: 10.0.0.2
+        0x00,
+        0x2a,
+        0x05,
+        0x39, // src port: 42, dst port: 1337
+    ]);
+    match format.protocol {
+        Protocol::Udp => {
+            frame.extend_from_slice(&(ip_len - 20).to_be_bytes()); // length
+            frame.extend_from_slice(&[0x00, 0x00]); // checksum: none
+        }
+        Protocol::Tcp => frame.extend_from_slice(&[
+            0x00, 0x00, 0x00, 0x00, // sequence number
+            0x00, 0x00, 0x00, 0x00, // acknowledgment number
+            0x50, 0x00, // data offset: 5, no flags
+            0xff, 0xff, // window
+            0x00, 0x00, // checksum (calculated below)
+            0x00, 0x00, // urgent pointer
+        ]),
+    }
+    frame.extend_from_slice(format.marker.as_bytes());
+    frame.resize(format.length, 0);
+    let seq_num_bytes = match format.seq_endianness {
+        Endianness::Little => u32::to_le_bytes(seq_num),
+        Endianness::Big => u32::to_be_bytes(seq_num),
+    };
+    frame[seq_num_range(format)].copy_from_slice(&seq_num_bytes);
+
+    let checksum = internet_checksum(&frame[14..TRANSPORT_OFFSET]);
+    frame[24..26].copy_from_slice(&checksum.to_be_bytes());
+    if format.protocol == Protocol::Tcp {
+        let checksum = internet_checksum(&transport_checksum_data(&frame));
+        let offset = checksum_offset(format.protocol);
+        frame[offset..(offset + 2)].copy_from_slice(&checksum.to_be_bytes());
+    }
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{build_pcap, build_pcap_from_frames, build_pcap_with_format};
+
+    fn test(seq_nums: &[u32], pcap_n: usize) -> Result<PcapReport, Error> {
+        test_pcap(
+            &build_pcap(seq_nums),
+            pcap_n,
+            &PacketFormat::default(),
+            &PcapCriteria::default(),
+        )
+    }
+
+    fn failures(result: Result<PcapReport, Error>) -> Vec<Failure> {
+        match result {
+            Err(Error::Failed { failures, .. }) => failures,
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_valid_pcap() {
+        let seq_nums: Vec<_> = (0..100).collect();
+        let report = test(&seq_nums, 100).unwrap();
+        assert_eq!(report.total_packets, 100);
+        assert_eq!(report.test_packets, 100);
+        assert_eq!(report.max_seq_num, Some(99));
+        assert!(report.missing.is_empty());
+    }
+
+    #[test]
+    fn test_missing_packets() {
+        let seq_nums: Vec<_> = (0..99).collect();
+        assert_eq!(
+            failures(test(&seq_nums, 100)),
+            vec![Failure::IncorrectPacketCount {
+                expected: 100,
+                actual: 99
+            }]
+        );
+    }
+
+    #[test]
+    fn test_duplicate_packets() {
+        let mut seq_nums: Vec<_> = (0..99).collect();
+        seq_nums.push(42);
+        assert_eq!(
+            failures(test(&seq_nums, 100)),
+            vec![Failure::TooManyDuplicates { count: 1, max: 0 }]
+        );
+
+        let criteria = PcapCriteria {
+            max_duplicates: 1,
+            ..PcapCriteria::default()
+        };
+        test_pcap(
+            &build_pcap(&seq_nums),
+            100,
+            &PacketFormat::default(),
+            &criteria,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_multiple_failures() {
+        let mut pcap = build_pcap(&[0, 1, 2, 5, 6, 6, 4]);
+        // Break the "ixy" marker of the last packet
+        let marker = pcap.len() - 18;
+        pcap[marker] = b'x';
+        let report = analyze_pcap(&pcap, &PacketFormat::default()).unwrap();
+        assert_eq!(report.total_packets, 7);
+        assert_eq!(report.malformed_packets, 1);
+        assert_eq!(report.malformed_samples[0].index, 6);
+        assert_eq!(report.malformed_samples[0].reason, "missing marker");
+        assert_eq!(report.duplicates, 1);
+        assert_eq!(report.missing, vec![SeqRange { start: 3, end: 4 }]);
+
+        let failures = report.evaluate(10, &PcapCriteria::default());
+        assert_eq!(
+            failures,
+            vec![
+                Failure::IncorrectPacketCount {
+                    expected: 10,
+                    actual: 7
+                },
+                Failure::BadSequenceNumber {
+                    packets: 10,
+                    max_seq_num: Some(6)
+                },
+                Failure::TooManyMalformedPackets { count: 1, max: 0 },
+                Failure::TooManyDuplicates { count: 1, max: 0 },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_frame_validation() {
+        let format = PacketFormat::default();
+        let valid = build_frame(&format, 42);
+        assert!(validate_frame(&valid, &format).is_empty());
+
+        // A correct UDP checksum is fine
+        let mut frame = valid.clone();
+        let checksum = internet_checksum(&transport_checksum_data(&frame));
+        frame[40..42].copy_from_slice(&checksum.to_be_bytes());
+        assert!(validate_frame(&frame, &format).is_empty());
+        frame[41] ^= 0xff;
+        assert_eq!(
+            validate_frame(&frame, &format),
+            vec![Mismatch::TransportChecksum]
+        );
+
+        // A changed header field breaks the IP checksum unless it's recalculated
+        let mut frame = valid.clone();
+        frame[22] = 63;
+        assert_eq!(
+            validate_frame(&frame, &format),
+            vec![Mismatch::Ttl, Mismatch::IpChecksum]
+        );
+        frame[24..26].copy_from_slice(&[0, 0]);
+        let checksum = internet_checksum(&frame[14..34]);
+        frame[24..26].copy_from_slice(&checksum.to_be_bytes());
+        assert_eq!(validate_frame(&frame, &format), vec![Mismatch::Ttl]);
+
+        let mut frame = valid.clone();
+        frame[0] = 0xff;
+        frame[11] = 0xff;
+        frame[50] = 1;
+        assert_eq!(
+            validate_frame(&frame, &format),
+            vec![Mismatch::DstMac, Mismatch::SrcMac, Mismatch::Padding]
+        );
+
+        // Trailing bytes (e.g. the FCS) are ignored, missing ones aren't
+        let mut frame = valid.clone();
+        frame.extend_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+        assert!(validate_frame(&frame, &format).is_empty());
+        assert_eq!(
+            validate_frame(&valid[..50], &format),
+            vec![Mismatch::FrameLength]
+        );
+    }
+
+    #[test]
+    fn test_trailing_fcs() {
+        let format = PacketFormat::default();
+        let frames: Vec<_> = (0..100)
+            .map(|seq_num| {
+                let mut frame = build_frame(&format, seq_num);
+                frame.extend_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+                frame
+            })
+            .collect();
+        let pcap = build_pcap_from_frames(&frames);
+        let report = test_pcap(&pcap, 100, &format, &PcapCriteria::default()).unwrap();
+        assert_eq!(report.malformed_packets, 0);
+        assert_eq!(report.max_seq_num, Some(99));
+    }
+
+    #[test]
+    fn test_packet_format() {
+        let format = PacketFormat {
+            protocol: Protocol::Tcp,
+            length: 64,
+            marker: "ixy.rs".to_string(),
+            seq_offset: 60,
+            seq_endianness: Endianness::Big,
+        };
+        let seq_nums: Vec<_> = (0..100).collect();
+        let pcap = build_pcap_with_format(&seq_nums, &format);
+        let report = test_pcap(&pcap, 100, &format, &PcapCriteria::default()).unwrap();
+        assert_eq!(report.malformed_packets, 0);
+        assert_eq!(report.max_seq_num, Some(99));
+
+        // Packets of another protocol are ignored
+        let report = analyze_pcap(&pcap, &PacketFormat::default()).unwrap();
+        assert_eq!(report.test_packets, 0);
+        assert_eq!(report.other_packets, 100);
+
+        // Frames of the wrong size or with a different marker are malformed
+        let format = PacketFormat {
+            length: 128,
+            ..PacketFormat::default()
+        };
+        let report = analyze_pcap(&build_pcap(&seq_nums), &format).unwrap();
+        assert_eq!(report.mismatches[&Mismatch::FrameLength], 100);
+        let format = PacketFormat {
+            marker: "ixz".to_string(),
+            ..PacketFormat::default()
+        };
+        let report = analyze_pcap(&build_pcap(&seq_nums), &format).unwrap();
+        assert_eq!(report.mismatches[&Mismatch::Marker], 100);
+
+        for seq_offset in &[40, 43, 58] {
+            let format = PacketFormat {
+                seq_offset: *seq_offset,
+                ..PacketFormat::default()
+            };
+            match analyze_pcap(&build_pcap(&seq_nums), &format) {
+                Err(Error::InvalidFormat { .. }) => {}
+                r => panic!("unexpected result: {:?}", r),
+            }
+        }
+        let format = PacketFormat {
+            length: 59,
+            seq_offset: 50,
+            ..PacketFormat::default()
+        };
+        match analyze_pcap(&build_pcap(&seq_nums), &format) {
+            Err(Error::InvalidFormat { .. }) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_corrupted_packets() {
+        let mut pcap = build_pcap(&[0, 1, 2, 3]);
+        let frame = |i: usize| 24 + i * (16 + 60) + 16;
+        // Source port of packet 1, padding of packets 2 and 3
+        pcap[frame(1) + 35] = 43;
+        pcap[frame(2) + 45] = 0xaa;
+        pcap[frame(3) + 55] = 0xaa;
+        let report = analyze_pcap(&pcap, &PacketFormat::default()).unwrap();
+        assert_eq!(report.malformed_packets, 3);
+        assert_eq!(report.mismatches.len(), 2);
+        assert_eq!(report.mismatches[&Mismatch::SrcPort], 1);
+        assert_eq!(report.mismatches[&Mismatch::Padding], 2);
+        assert_eq!(report.max_seq_num, Some(0));
+    }
+
+    #[test]
+    fn test_loss() {
+        let seq_nums: Vec<_> = (10..20).chain(30..35).chain(36..40).collect();
+        let report = analyze_pcap(&build_pcap(&seq_nums), &PacketFormat::default()).unwrap();
+        assert_eq!(
+            report.missing,
+            vec![
+                SeqRange { start: 20, end: 29 },
+                SeqRange { start: 35, end: 35 }
+            ]
+        );
+        assert_eq!(report.missing_count(), 11);
+        assert!((report.loss_ratio() - 11.0 / 30.0).abs() < 1e-9);
+        assert_eq!(
+            report.biggest_gaps(1),
+            vec![SeqRange { start: 20, end: 29 }]
+        );
+
+        let criteria = PcapCriteria {
+            max_loss_ratio: Some(0.4),
+            ..PcapCriteria::default()
+        };
+        assert!(report.evaluate(19, &criteria).is_empty());
+        let criteria = PcapCriteria {
+            max_loss_ratio: Some(0.1),
+            ..PcapCriteria::default()
+        };
+        assert_eq!(
+            report.evaluate(19, &criteria),
+            vec![Failure::TooMuchLoss {
+                missing: 11,
+                ratio: 11.0 / 30.0,
+                max: 0.1
+            }]
+        );
+    }
+
+    #[test]
+    fn test_reordering() {
+        let report =
+            analyze_pcap(&build_pcap(&[0, 2, 1, 3, 5, 4]), &PacketFormat::default()).unwrap();
+        assert_eq!(
+            report.reordering,
+            ReorderStats {
+                out_of_order: 2,
+                inversions: 2,
+                max_displacement: 1,
+                longest_in_order_run: 3,
+            }
+        );
+        assert!(report.missing.is_empty());
+
+        // One packet overtook all others
+        let stats = ReorderStats::calculate(&[9, 0, 1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(stats.out_of_order, 9);
+        assert_eq!(stats.inversions, 9);
+        assert_eq!(stats.max_displacement, 9);
+        assert_eq!(stats.longest_in_order_run, 9);
+
+        let stats = ReorderStats::calculate(&[3, 2, 1, 0]);
+        assert_eq!(stats.inversions, 6);
+        assert_eq!(stats.longest_in_order_run, 1);
+        assert_eq!(ReorderStats::calculate(&[]), ReorderStats::default());
+    }
+
+    #[test]
+    fn test_reorder_tolerance() {
+        let mut seq_nums: Vec<_> = (0..100).collect();
+        seq_nums.swap(10, 11);
+        seq_nums.swap(50, 60);
+        let pcap = build_pcap(&seq_nums);
+        // Reordering isn't checked by default
+        test_pcap(
+            &pcap,
+            100,
+            &PacketFormat::default(),
+            &PcapCriteria::default(),
+        )
+        .unwrap();
+
+        let criteria = PcapCriteria {
+            max_inversions: Some(20),
+            max_displacement: Some(10),
+            ..PcapCriteria::default()
+        };
+        test_pcap(&pcap, 100, &PacketFormat::default(), &criteria).unwrap();
+
+        let criteria = PcapCriteria {
+            max_inversions: Some(5),
+            max_displacement: Some(5),
+            ..PcapCriteria::default()
+        };
+        assert_eq!(
+            failures(test_pcap(&pcap, 100, &PacketFormat::default(), &criteria)),
+            vec![
+                Failure::TooManyInversions { count: 20, max: 5 },
+                Failure::DisplacementTooLarge {
+                    displacement: 10,
+                    max: 5
+                },
+            ]
+        );
+    }
 }
@@ -1,23 +1,153 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use chrono::{SecondsFormat, Utc};
+use futures::future::{self, Shared};
 use futures::Future;
+use hubcaps::checks::{
+    Annotation, AnnotationLevel, CheckRunOptions, CheckRunState, CheckRunUpdateOptions, Conclusion,
+    Output,
+};
 use hubcaps::comments::CommentOptions;
+use hubcaps::statuses::{State, StatusOptions};
 use hubcaps::Github;
 use log::*;
+use serde::{Deserialize, Serialize};
 use url::Url;
 
+use crate::config::Repository;
 use crate::remote::Log;
 use crate::worker::{Report, ReportContent, TestError, TestOutput, TestTarget};
 
+const CHECK_RUN_NAME: &str = "ixy-ci";
+const STATUS_CONTEXT: &str = "ixy-ci";
+// GitHub rejects annotation messages larger than 64 KiB
+const MAX_ANNOTATION_LENGTH: usize = 64 * 1024;
+
+type CheckRunId = Shared<Box<dyn Future<Item = String, Error = ()>>>;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredCheckRun {
+    repository: Repository,
+    sha: String,
+    id: String,
+}
+
+/// Ids of the created check runs which haven't been completed yet. They're persisted to disk so
+/// that jobs which are queued again after a restart update their existing check run instead of
+/// leaving it behind.
+pub struct CheckRunStore {
+    path: PathBuf,
+    ids: RefCell<HashMap<(Repository, String), String>>,
+}
+
+impl CheckRunStore {
+    pub fn open(path: PathBuf) -> Result<CheckRunStore, io::Error> {
+        let stored: Vec<StoredCheckRun> = match fs::read(&path) {
+            Ok(content) => serde_json::from_slice(&content)?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        let ids = stored
+            .into_iter()
+            .map(|check_run| ((check_run.repository, check_run.sha), check_run.id))
+            .collect();
+        Ok(CheckRunStore {
+            path,
+            ids: RefCell::new(ids),
+        })
+    }
+
+    fn insert(&self, repository: Repository, sha: String, id: String) {
+        let mut ids = self.ids.borrow_mut();
+        ids.insert((repository, sha), id);
+        self.persist(&ids);
+    }
+
+    fn remove(&self, key: &(Repository, String)) {
+        let mut ids = self.ids.borrow_mut();
+        if ids.remove(key).is_some() {
+            self.persist(&ids);
+        }
+    }
+
+    fn persist(&self, ids: &HashMap<(Repository, String), String>) {
+        let stored: Vec<_> = ids
+            .iter()
+            .map(|((repository, sha), id)| StoredCheckRun {
+                repository: repository.clone(),
+                sha: sha.clone(),
+                id: id.clone(),
+            })
+            .collect();
+        // Write to a temporary file first so that we never end up with a half-written file
+        let tmp_path = self.path.with_extension("tmp");
+        let result = serde_json::to_vec(&stored)
+            .map_err(io::Error::from)
+            .and_then(|content| fs::write(&tmp_path, content))
+            .and_then(|_| fs::rename(&tmp_path, &self.path));
+        if let Err(e) = result {
+            error!("Failed to persist check runs: {}", e);
+        }
+    }
+}
+
 pub struct Publisher {
     github: Github,
     public_url: Url,
+    /// GitHub App clients for the Checks API keyed by the user/organization they're installed for
+    check_clients: HashMap<String, Github>,
+    /// Check runs which haven't been completed yet keyed by repository and commit
+    check_runs: RefCell<HashMap<(Repository, String), CheckRunId>>,
+    /// The ids of `check_runs` once they've been created
+    check_run_store: Rc<CheckRunStore>,
 }
 
 impl Publisher {
-    pub fn new(github: Github, public_url: Url) -> Publisher {
-        Publisher { github, public_url }
+    pub fn new(
+        github: Github,
+        public_url: Url,
+        check_clients: HashMap<String, Github>,
+        check_run_store: CheckRunStore,
+    ) -> Publisher {
+        let check_runs = check_run_store
+            .ids
+            .borrow()
+            .iter()
+            .map(|(key, id)| {
+                let id: Box<dyn Future<Item = String, Error = ()>> =
+                    Box::new(future::ok(id.clone()));
+                (key.clone(), id.shared())
+            })
+            .collect();
+        Publisher {
+            github,
+            public_url,
+            check_clients,
+            check_runs: RefCell::new(check_runs),
+            check_run_store: Rc::new(check_run_store),
+        }
     }
 
     pub fn handle_report(&self, report: Report) -> Box<dyn Future<Item = (), Error = ()>> {
+        // Failing to update the commit status shouldn't prevent the actual report
+        let status = self.update_commit_status(&report).then(|_| Ok::<_, ()>(()));
+        Box::new(status.join(self.publish_report(report)).map(|_| {}))
+    }
+
+    fn publish_report(&self, report: Report) -> Box<dyn Future<Item = (), Error = ()>> {
+        let check_client = match report.content {
+            ReportContent::Pong { .. } => None,
+            _ => self.check_clients.get(&report.repository.user),
+        };
+        if let Some(check_client) = check_client {
+            return self.handle_check_run_report(check_client, report);
+        }
+
         match report.content {
             ReportContent::Pong { issue_id } => Box::new(
                 self.github
@@ -31,9 +161,13 @@ impl Publisher {
                     .map_err(|e| error!("Failed to post comment: {:?}", e))
                     .map(|_| {}),
             ),
+            ReportContent::Queued { .. }
+            | ReportContent::Started { .. }
+            | ReportContent::Cancelled { .. } => Box::new(futures::future::ok(())),
             ReportContent::TestResult {
                 result,
                 test_target,
+                sha,
             } => match test_target {
                 TestTarget::PullRequest(id) => {
                     info!("Posting result in {}#{}", report.repository, id);
@@ -44,7 +178,7 @@ impl Publisher {
                             .get(id)
                             .comments()
                             .create(&CommentOptions {
-                                body: self.format_pull_request_comment(result),
+                                body: self.format_pull_request_comment(result, &sha),
                             })
                             .map_err(|e| error!("Failed to post comment: {:?}", e))
                             .map(|_| {}),
@@ -52,9 +186,10 @@ impl Publisher {
                 }
                 TestTarget::Branch(branch) => {
                     info!(
-                        "Test result for branch {} of {}: {}",
+                        "Test result for branch {} of {} ({}): {}",
                         branch,
                         report.repository,
+                        sha,
                         result.is_ok()
                     );
                     if let Err(e) = result {
@@ -66,11 +201,334 @@ impl Publisher {
         }
     }
 
-    fn format_pull_request_comment(&self, result: Result<TestOutput, TestError>) -> String {
+    fn update_commit_status(&self, report: &Report) -> Box<dyn Future<Item = (), Error = ()>> {
+        let (sha, options) = match &report.content {
+            ReportContent::Pong { .. } => return Box::new(futures::future::ok(())),
+            ReportContent::Queued { sha, .. } => (
+                sha,
+                StatusOptions::builder(State::Pending)
+                    .description("Waiting in queue")
+                    .context(STATUS_CONTEXT)
+                    .build(),
+            ),
+            ReportContent::Started { sha, .. } => (
+                sha,
+                StatusOptions::builder(State::Pending)
+                    .description("Test is running")
+                    .context(STATUS_CONTEXT)
+                    .build(),
+            ),
+            ReportContent::Cancelled {
+                sha, superseded_by, ..
+            } => (
+                sha,
+                StatusOptions::builder(State::Error)
+                    .description(cancelled_description(superseded_by))
+                    .context(STATUS_CONTEXT)
+                    .build(),
+            ),
+            ReportContent::TestResult { result, sha, .. } => {
+                let (state, description, log_file) = match result {
+                    Ok(test_output) => (State::Success, "Test passed", Some(&test_output.log_file)),
+                    Err(TestError::PerformTest { test_output, .. }) => {
+                        (State::Failure, "Test failed", Some(&test_output.log_file))
+                    }
+                    Err(_) => (State::Error, "An error occurred while testing", None),
+                };
+                let mut builder = StatusOptions::builder(state);
+                builder.description(description).context(STATUS_CONTEXT);
+                if let Some(url) = log_file.and_then(|log_file| self.log_url(log_file)) {
+                    builder.target_url(url);
+                }
+                (sha, builder.build())
+            }
+        };
+        info!("Setting commit status of {} ({})", report.repository, sha);
+        Box::new(
+            self.github
+                .repo(
+                    report.repository.user.clone(),
+                    report.repository.name.clone(),
+                )
+                .statuses()
+                .create(sha, &options)
+                .map_err(|e| error!("Failed to set commit status: {:?}", e))
+                .map(|_| {}),
+        )
+    }
+
+    fn handle_check_run_report(
+        &self,
+        check_client: &Github,
+        report: Report,
+    ) -> Box<dyn Future<Item = (), Error = ()>> {
+        let check_runs = check_client
+            .repo(
+                report.repository.user.clone(),
+                report.repository.name.clone(),
+            )
+            .checkruns();
+        match report.content {
+            ReportContent::Pong { .. } => unreachable!("pongs aren't related to any commit"),
+            ReportContent::Queued { sha, .. } => {
+                let key = (report.repository, sha);
+                // Another job for the same commit may already have a check run
+                let existing = self.check_runs.borrow().get(&key).cloned();
+                let check_run_id = match existing {
+                    Some(check_run_id) => check_run_id,
+                    None => {
+                        info!("Creating check run for {} ({})", key.0, key.1);
+                        let check_run_id = self.create_check_run(
+                            &check_runs,
+                            &key.0,
+                            &key.1,
+                            CheckRunState::Queued,
+                        );
+                        self.check_runs
+                            .borrow_mut()
+                            .insert(key, check_run_id.clone());
+                        check_run_id
+                    }
+                };
+                Box::new(check_run_id.map(|_| {}).map_err(|_| {}))
+            }
+            ReportContent::Started { sha, .. } => {
+                let check_run_id = self.get_check_run(&check_runs, &report.repository, &sha);
+                Box::new(
+                    check_run_id
+                        .map_err(|_| {})
+                        .and_then(move |check_run_id| {
+                            check_runs
+                                .update(
+                                    &*check_run_id,
+                                    &CheckRunUpdateOptions {
+                                        name: None,
+                                        details_url: None,
+                                        external_id: None,
+                                        status: Some(CheckRunState::InProgress),
+                                        started_at: Some(now()),
+                                        conclusion: None,
+                                        completed_at: None,
+                                        output: None,
+                                        actions: None,
+                                    },
+                                )
+                                .map_err(|e| error!("Failed to update check run: {:?}", e))
+                        })
+                        .map(|_| {}),
+                )
+            }
+            ReportContent::TestResult { result, sha, .. } => {
+                info!("Completing check run for {} ({})", report.repository, sha);
+                let check_run_id = self.take_check_run(&check_runs, report.repository, sha);
+                let (conclusion, details_url, output) = self.format_check_run_output(result);
+                Box::new(
+                    check_run_id
+                        .and_then(move |check_run_id| {
+                            check_runs
+                                .update(
+                                    &*check_run_id,
+                                    &CheckRunUpdateOptions {
+                                        name: None,
+                                        details_url,
+                                        external_id: None,
+                                        status: Some(CheckRunState::Completed),
+                                        started_at: None,
+                                        conclusion: Some(conclusion),
+                                        completed_at: Some(now()),
+                                        output: Some(output),
+                                        actions: None,
+                                    },
+                                )
+                                .map_err(|e| error!("Failed to complete check run: {:?}", e))
+                        })
+                        .map(|_| {}),
+                )
+            }
+            ReportContent::Cancelled {
+                sha, superseded_by, ..
+            } => {
+                info!("Cancelling check run for {} ({})", report.repository, sha);
+                let title = cancelled_description(&superseded_by);
+                let check_run_id = self.take_check_run(&check_runs, report.repository, sha);
+                Box::new(
+                    check_run_id
+                        .and_then(move |check_run_id| {
+                            check_runs
+                                .update(
+                                    &*check_run_id,
+                                    &CheckRunUpdateOptions {
+                                        name: None,
+                                        details_url: None,
+                                        external_id: None,
+                                        status: Some(CheckRunState::Completed),
+                                        started_at: None,
+                                        conclusion: Some(Conclusion::Cancelled),
+                                        completed_at: Some(now()),
+                                        output: Some(Output {
+                                            summary: format!("{}.", title),
+                                            title,
+                                            text: None,
+                                            annotations: None,
+                                            images: None,
+                                        }),
+                                        actions: None,
+                                    },
+                                )
+                                .map_err(|e| error!("Failed to cancel check run: {:?}", e))
+                        })
+                        .map(|_| {}),
+                )
+            }
+        }
+    }
+
+    /// Returns the id of the check run for the given commit (and creates one if necessary).
+    fn get_check_run(
+        &self,
+        check_runs: &hubcaps::checks::CheckRuns,
+        repository: &Repository,
+        sha: &str,
+    ) -> CheckRunId {
+        let key = (repository.clone(), sha.to_string());
+        if let Some(check_run_id) = self.check_runs.borrow().get(&key) {
+            return check_run_id.clone();
+        }
+        let check_run_id =
+            self.create_check_run(check_runs, repository, sha, CheckRunState::InProgress);
+        self.check_runs
+            .borrow_mut()
+            .insert(key, check_run_id.clone());
+        check_run_id
+    }
+
+    /// Returns the id of the check run for the given commit (like `get_check_run`) and forgets it
+    /// as it's about to be completed.
+    fn take_check_run(
+        &self,
+        check_runs: &hubcaps::checks::CheckRuns,
+        repository: Repository,
+        sha: String,
+    ) -> impl Future<Item = String, Error = ()> {
+        let check_run_id = self.get_check_run(check_runs, &repository, &sha);
+        let key = (repository, sha);
+        self.check_runs.borrow_mut().remove(&key);
+        // The id is only stored once the check run has been created
+        let check_run_store = Rc::clone(&self.check_run_store);
+        check_run_id
+            .map(move |check_run_id| {
+                check_run_store.remove(&key);
+                (*check_run_id).clone()
+            })
+            .map_err(|_| {})
+    }
+
+    fn create_check_run(
+        &self,
+        check_runs: &hubcaps::checks::CheckRuns,
+        repository: &Repository,
+        sha: &str,
+        status: CheckRunState,
+    ) -> CheckRunId {
+        let (check_run_store, repository, head_sha) = (
+            Rc::clone(&self.check_run_store),
+            repository.clone(),
+            sha.to_string(),
+        );
+        let future: Box<dyn Future<Item = String, Error = ()>> = Box::new(
+            check_runs
+                .create(&CheckRunOptions {
+                    name: CHECK_RUN_NAME.to_string(),
+                    head_sha: sha.to_string(),
+                    details_url: None,
+                    external_id: None,
+                    status: Some(status),
+                    started_at: None,
+                    conclusion: None,
+                    completed_at: None,
+                    output: None,
+                    actions: None,
+                })
+                .map(move |check_run| {
+                    let id = check_run.id.to_string();
+                    check_run_store.insert(repository, head_sha, id.clone());
+                    id
+                })
+                .map_err(|e| error!("Failed to create check run: {:?}", e)),
+        );
+        future.shared()
+    }
+
+    fn format_check_run_output(
+        &self,
+        result: Result<TestOutput, TestError>,
+    ) -> (Conclusion, Option<String>, Output) {
         match result {
-            Ok(test_output) => format!("Test __passed__!\n\n{}", self.format_logs(&test_output)),
+            Ok(test_output) => (
+                Conclusion::Success,
+                self.log_url(&test_output.log_file),
+                Output {
+                    title: "Test passed".to_string(),
+                    summary: self.format_pcap_link(&test_output),
+                    text: None,
+                    annotations: Some(format_annotations(&test_output, true)),
+                    images: None,
+                },
+            ),
+            Err(TestError::PerformTest {
+                source,
+                test_output,
+            }) => (
+                Conclusion::Failure,
+                self.log_url(&test_output.log_file),
+                Output {
+                    title: "Test failed".to_string(),
+                    summary: format!(
+                        "Cause: {}\n\n{}",
+                        source,
+                        self.format_pcap_link(&test_output)
+                    ),
+                    text: None,
+                    annotations: Some(format_annotations(&test_output, false)),
+                    images: None,
+                },
+            ),
+            Err(e) => (
+                Conclusion::Failure,
+                None,
+                Output {
+                    title: "Test failed".to_string(),
+                    summary: format!("Cause: {}", e),
+                    text: None,
+                    annotations: None,
+                    images: None,
+                },
+            ),
+        }
+    }
+
+    fn log_url(&self, file: &str) -> Option<String> {
+        self.public_url
+            .join("logs/")
+            .and_then(|url| url.join(file))
+            .map(|url| url.to_string())
+            .ok()
+    }
+
+    fn format_pull_request_comment(
+        &self,
+        result: Result<TestOutput, TestError>,
+        sha: &str,
+    ) -> String {
+        match result {
+            Ok(test_output) => format!(
+                "Test of {} __passed__!\n\n{}",
+                sha,
+                self.format_logs(&test_output)
+            ),
             Err(test_error) => format!(
-                "Test __failed__!\n\nCause: {}",
+                "Test of {} __failed__!\n\nCause: {}",
+                sha,
                 match test_error {
                     TestError::PerformTest {
                         source,
@@ -87,24 +545,75 @@ impl Publisher {
     fn format_logs(&self, test_output: &TestOutput) -> String {
         format!(
             "{}\n\n{}\n{}\n{}",
-            if let Some(pcap_file) = &test_output.pcap_file {
-                format!(
-                    "The captured `.pcap` can be downloaded [here]({}).",
-                    self.public_url
-                        .join("logs/")
-                        .unwrap()
-                        .join(pcap_file)
-                        .map(|url| url.to_string())
-                        .unwrap_or_else(|_| "URL error".to_string())
-                )
-            } else {
-                "The test failed before a `.pcap` was captured".to_string()
-            },
+            self.format_pcap_link(test_output),
             format_log("pktgen", &test_output.log_pktgen),
             format_log("fwd", &test_output.log_fwd),
             format_log("pcap", &test_output.log_pcap)
         )
     }
+
+    fn format_pcap_link(&self, test_output: &TestOutput) -> String {
+        if let Some(pcap_file) = &test_output.pcap_file {
+            format!(
+                "The captured `.pcap` can be downloaded [here]({}).",
+                self.log_url(pcap_file)
+                    .unwrap_or_else(|| "URL error".to_string())
+            )
+        } else {
+            "The test failed before a `.pcap` was captured".to_string()
+        }
+    }
+}
+
+fn now() -> String {
+    Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+fn cancelled_description(superseded_by: &Option<String>) -> String {
+    match superseded_by {
+        Some(sha) => format!("Test was superseded by a test of {}", sha),
+        None => "Test was cancelled".to_string(),
+    }
+}
+
+/// Attaches the log of each VM as an annotation to the repository's `ixy-ci.toml`.
+fn format_annotations(test_output: &TestOutput, passed: bool) -> Vec<Annotation> {
+    [
+        ("pktgen", &test_output.log_pktgen),
+        ("fwd", &test_output.log_fwd),
+        ("pcap", &test_output.log_pcap),
+    ]
+    .iter()
+    .map(|(name, log)| {
+        let mut message = String::new();
+        for (command, output) in log.iter() {
+            message += &format!("$ {}\n{}\n\n", command, output);
+        }
+        if message.len() > MAX_ANNOTATION_LENGTH {
+            // Keep the end of the log as that's where errors usually show up
+            let mut start = message.len() - MAX_ANNOTATION_LENGTH;
+            while !message.is_char_boundary(start) {
+                start += 1;
+            }
+            message = message[start..].to_string();
+        }
+        Annotation {
+            path: "ixy-ci.toml".to_string(),
+            start_line: 1,
+            end_line: 1,
+            start_column: None,
+            end_column: None,
+            annotation_level: if passed {
+                AnnotationLevel::Notice
+            } else {
+                AnnotationLevel::Warning
+            },
+            message: message.trim().to_string(),
+            title: Some(format!("{} logs", name)),
+            raw_details: None,
+        }
+    })
+    .collect()
 }
 
 // `Log` is currently just a type alias for `Vec` so `&Log` becomes `&Vec` which clippy doesn't like
@@ -120,3 +629,29 @@ fn format_log(name: &str, log: &Log) -> String {
         log_content.trim()
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn test_check_run_store() {
+        let path = testing::temp_dir("check-runs", "reopen").join("check_runs.json");
+        let repository = Repository {
+            user: "ixy-languages".to_string(),
+            name: "ixy.rs".to_string(),
+        };
+
+        let store = CheckRunStore::open(path.clone()).unwrap();
+        store.insert(repository.clone(), "1111".to_string(), "1".to_string());
+        store.insert(repository.clone(), "2222".to_string(), "2".to_string());
+        store.remove(&(repository.clone(), "1111".to_string()));
+
+        // The ids survive a restart
+        let store = CheckRunStore::open(path).unwrap();
+        let ids = store.ids.borrow();
+        assert_eq!(ids.len(), 1);
+        assert_eq!(ids[&(repository, "2222".to_string())], "2");
+    }
+}
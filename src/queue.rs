@@ -0,0 +1,401 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Condvar, Mutex};
+
+use chrono::{DateTime, Utc};
+use crossbeam_channel::Sender;
+use log::*;
+use serde::{Deserialize, Serialize};
+use snafu::Snafu;
+
+use crate::worker::{send_report, Job, Report};
+
+pub type JobId = u64;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("The job queue is full"))]
+    QueueFull,
+}
+
+/// A job which has been added to the queue
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pushed {
+    pub id: JobId,
+    /// Whether the job replaced a queued job which tests the same commit (in which case neither
+    /// job is reported)
+    pub replaced_same_commit: bool,
+}
+
+/// How many jobs were affected by a cancellation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled {
+    /// Queued jobs which were removed
+    pub queued: usize,
+    /// Running jobs which will be aborted at their next phase boundary
+    pub running: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueuedJob {
+    pub id: JobId,
+    pub job: Job,
+    pub queued_at: DateTime<Utc>,
+    /// Whether this job was already running when ixy-ci was shut down
+    #[serde(default)]
+    pub interrupted: bool,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct State {
+    next_id: JobId,
+    queued: VecDeque<QueuedJob>,
+    running: Vec<QueuedJob>,
+    /// Running jobs which should be aborted by their worker together with the commit of the job
+    /// which superseded them (if any)
+    #[serde(default)]
+    cancelled: HashMap<JobId, Option<String>>,
+}
+
+/// A job queue which is persisted to disk on every change so that no jobs get lost when ixy-ci
+/// is restarted.
+pub struct JobQueue {
+    path: PathBuf,
+    capacity: usize,
+    /// Whether new jobs also cancel running jobs for the same target (queued ones are always
+    /// replaced)
+    supersede_running: bool,
+    state: Mutex<State>,
+    job_available: Condvar,
+}
+
+impl JobQueue {
+    /// Opens the queue stored at `path` (or creates a new one). Jobs which were running when the
+    /// queue was last persisted are marked as interrupted and queued again (unless they were
+    /// cancelled).
+    pub fn open(
+        path: PathBuf,
+        capacity: usize,
+        supersede_running: bool,
+    ) -> Result<JobQueue, io::Error> {
+        let mut state = match fs::read(&path) {
+            Ok(content) => serde_json::from_slice(&content)?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => State::default(),
+            Err(e) => return Err(e),
+        };
+
+        for mut job in state.running.drain(..).rev() {
+            if state.cancelled.contains_key(&job.id) {
+                info!("Dropping interrupted job {} which was cancelled", job.id);
+                continue;
+            }
+            warn!("Queueing interrupted job {} again", job.id);
+            job.interrupted = true;
+            state.queued.push_front(job);
+        }
+        state.cancelled.clear();
+        info!("Restored {} queued jobs", state.queued.len());
+
+        let queue = JobQueue {
+            path,
+            capacity,
+            supersede_running,
+            state: Mutex::new(state),
+            job_available: Condvar::new(),
+        };
+        queue.persist(&queue.state.lock().unwrap());
+        Ok(queue)
+    }
+
+    /// Adds a job to the queue and reports it as queued. A queued job which tests the same target
+    /// is superseded by the new job which takes over its position in the queue. If both test the
+    /// same commit the old job's reports stay valid so nothing is reported at all.
+    pub fn push(&self, job: Job, report_sender: &Sender<Report>) -> Result<Pushed, Error> {
+        let mut state = self.state.lock().unwrap();
+        let superseded = job.test_target().and_then(|(repository, target, _)| {
+            state
+                .queued
+                .iter()
+                .position(|queued_job| queued_job.job.tests(repository, &target))
+        });
+        if superseded.is_none() && state.queued.len() >= self.capacity {
+            return Err(Error::QueueFull);
+        }
+        let id = state.next_id;
+        state.next_id += 1;
+        let queued_job = QueuedJob {
+            id,
+            job,
+            queued_at: Utc::now(),
+            interrupted: false,
+        };
+
+        let (mut cancelled_report, mut replaced_same_commit) = (None, false);
+        if let Some((repository, target, sha)) = queued_job.job.test_target() {
+            if self.supersede_running {
+                let running: Vec<_> = state
+                    .running
+                    .iter()
+                    .filter(|running| match running.job.test_target() {
+                        // A running test of the same commit isn't outdated
+                        Some((_, _, running_sha)) => {
+                            running.job.tests(repository, &target) && running_sha != sha
+                        }
+                        None => false,
+                    })
+                    .map(|running| running.id)
+                    .collect();
+                for running in running {
+                    info!("Running job {} is superseded by job {}", running, id);
+                    state.cancelled.insert(running, Some(sha.to_string()));
+                }
+            }
+            if let Some(index) = superseded {
+                let old = std::mem::replace(&mut state.queued[index], queued_job.clone());
+                info!("Queued job {} is superseded by job {}", old.id, id);
+                replaced_same_commit = match old.job.test_target() {
+                    Some((_, _, old_sha)) => old_sha == sha,
+                    None => false,
+                };
+                if !replaced_same_commit {
+                    cancelled_report = old.job.cancelled_report(Some(sha.to_string()));
+                }
+            }
+        }
+        // Report the job while holding the lock so that no worker can report its start first
+        if !replaced_same_commit {
+            if let Some(report) = queued_job.job.queued_report() {
+                send_report(report_sender, report);
+            }
+        }
+        if superseded.is_none() {
+            state.queued.push_back(queued_job);
+        }
+        self.persist(&state);
+        self.job_available.notify_one();
+        drop(state);
+
+        if let Some(report) = cancelled_report {
+            send_report(report_sender, report);
+        }
+        Ok(Pushed {
+            id,
+            replaced_same_commit,
+        })
+    }
+
+    /// Blocks until a job is available and marks it as running.
+    pub fn pop(&self) -> QueuedJob {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(job) = state.queued.pop_front() {
+                state.running.push(job.clone());
+                self.persist(&state);
+                return job;
+            }
+            state = self.job_available.wait(state).unwrap();
+        }
+    }
+
+    /// Removes a running job from the queue once it has been processed.
+    pub fn finish(&self, id: JobId) {
+        let mut state = self.state.lock().unwrap();
+        state.running.retain(|job| job.id != id);
+        state.cancelled.remove(&id);
+        self.persist(&state);
+    }
+
+    /// Cancels all jobs matching `predicate` and returns how many there were. Queued jobs are
+    /// removed right away (and reported as cancelled) while running jobs are aborted by their
+    /// worker at the next phase boundary.
+    pub fn cancel<P: Fn(&QueuedJob) -> bool>(
+        &self,
+        predicate: P,
+        report_sender: &Sender<Report>,
+    ) -> Cancelled {
+        let mut state = self.state.lock().unwrap();
+        let (removed, queued): (Vec<_>, Vec<_>) = state.queued.drain(..).partition(&predicate);
+        state.queued = queued.into();
+        let running: Vec<_> = state
+            .running
+            .iter()
+            .filter(|queued_job| predicate(queued_job))
+            .map(|queued_job| queued_job.id)
+            .collect();
+        for id in &running {
+            state.cancelled.insert(*id, None);
+        }
+        self.persist(&state);
+        drop(state);
+
+        for queued_job in &removed {
+            info!("Cancelled queued job {}", queued_job.id);
+            if let Some(report) = queued_job.job.cancelled_report(None) {
+                send_report(report_sender, report);
+            }
+        }
+        for id in &running {
+            info!("Cancelling running job {}", id);
+        }
+        Cancelled {
+            queued: removed.len(),
+            running: running.len(),
+        }
+    }
+
+    /// Returns whether the given running job has been cancelled.
+    pub fn is_cancelled(&self, id: JobId) -> bool {
+        self.state.lock().unwrap().cancelled.contains_key(&id)
+    }
+
+    /// Returns the commit of the job which superseded the given running job.
+    pub fn superseded_by(&self, id: JobId) -> Option<String> {
+        self.state
+            .lock()
+            .unwrap()
+            .cancelled
+            .get(&id)
+            .and_then(Clone::clone)
+    }
+
+    /// Returns the queued jobs in the order in which they'll be processed.
+    pub fn queued_jobs(&self) -> Vec<QueuedJob> {
+        self.state.lock().unwrap().queued.iter().cloned().collect()
+    }
+
+    pub fn queue_size(&self) -> usize {
+        self.state.lock().unwrap().queued.len()
+    }
+
+    fn persist(&self, state: &State) {
+        // Write to a temporary file first so that we never end up with a half-written queue
+        let tmp_path = self.path.with_extension("tmp");
+        let result = serde_json::to_vec(state)
+            .map_err(io::Error::from)
+            .and_then(|content| fs::write(&tmp_path, content))
+            .and_then(|_| fs::rename(&tmp_path, &self.path));
+        if let Err(e) = result {
+            error!("Failed to persist job queue: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Repository;
+    use crate::testing;
+    use crate::worker::{ReportContent, TestOptions};
+
+    fn test_branch(sha: &str) -> Job {
+        Job::TestBranch {
+            repository: Repository {
+                user: "ixy-languages".to_string(),
+                name: "ixy.rs".to_string(),
+            },
+            branch: "master".to_string(),
+            sha: sha.to_string(),
+            requested_by: "bobo1239".to_string(),
+            options: TestOptions::default(),
+        }
+    }
+
+    fn open(name: &str, supersede_running: bool) -> JobQueue {
+        let path = testing::temp_dir("queue", name).join("queue.json");
+        JobQueue::open(path, 1, supersede_running).unwrap()
+    }
+
+    #[test]
+    fn test_supersede_queued() {
+        let queue = open("supersede_queued", false);
+        let (report_sender, report_receiver) = crossbeam_channel::unbounded();
+        queue.push(test_branch("1111"), &report_sender).unwrap();
+        // The queue is full but the new job replaces the old one
+        let pushed = queue.push(test_branch("2222"), &report_sender).unwrap();
+        assert!(!pushed.replaced_same_commit);
+
+        let queued = queue.queued_jobs();
+        assert_eq!(queued.len(), 1);
+        assert_eq!(queued[0].id, pushed.id);
+        for sha in &["1111", "2222"] {
+            match report_receiver.try_recv().unwrap().content {
+                ReportContent::Queued { sha: queued, .. } => assert_eq!(queued, *sha),
+                content => panic!("unexpected report: {:?}", content),
+            }
+        }
+        match report_receiver.try_recv().unwrap().content {
+            ReportContent::Cancelled {
+                sha, superseded_by, ..
+            } => {
+                assert_eq!(sha, "1111");
+                assert_eq!(superseded_by.as_ref().map(String::as_str), Some("2222"));
+            }
+            content => panic!("unexpected report: {:?}", content),
+        }
+    }
+
+    #[test]
+    fn test_supersede_queued_same_sha() {
+        let queue = open("supersede_queued_same_sha", false);
+        let (report_sender, report_receiver) = crossbeam_channel::unbounded();
+        queue.push(test_branch("1111"), &report_sender).unwrap();
+        report_receiver.try_recv().unwrap();
+        let pushed = queue.push(test_branch("1111"), &report_sender).unwrap();
+        assert!(pushed.replaced_same_commit);
+
+        let queued = queue.queued_jobs();
+        assert_eq!(queued.len(), 1);
+        assert_eq!(queued[0].id, pushed.id);
+        // Neither the old job is reported as cancelled nor the new one as queued
+        assert!(report_receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_supersede_running() {
+        for &supersede_running in &[false, true] {
+            let queue = open("supersede_running", supersede_running);
+            let report_sender = crossbeam_channel::unbounded().0;
+            queue.push(test_branch("1111"), &report_sender).unwrap();
+            let running = queue.pop();
+            queue.push(test_branch("1111"), &report_sender).unwrap();
+            assert!(!queue.is_cancelled(running.id));
+
+            queue.push(test_branch("2222"), &report_sender).unwrap();
+            assert_eq!(queue.is_cancelled(running.id), supersede_running);
+            assert_eq!(queue.queued_jobs().len(), 1);
+            if supersede_running {
+                assert_eq!(queue.superseded_by(running.id), Some("2222".to_string()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_cancel() {
+        let queue = open("cancel", false);
+        let (report_sender, report_receiver) = crossbeam_channel::unbounded();
+        queue.push(test_branch("1111"), &report_sender).unwrap();
+        let running = queue.pop();
+        queue.push(test_branch("2222"), &report_sender).unwrap();
+
+        let cancelled = queue.cancel(|_| true, &report_sender);
+        assert_eq!(
+            cancelled,
+            Cancelled {
+                queued: 1,
+                running: 1
+            }
+        );
+        assert!(queue.queued_jobs().is_empty());
+        assert!(queue.is_cancelled(running.id));
+        assert_eq!(queue.superseded_by(running.id), None);
+        // Only the removed job is reported, the running one reports its own cancellation
+        let reports: Vec<_> = report_receiver.try_iter().collect();
+        assert_eq!(reports.len(), 3);
+        match &reports[2].content {
+            ReportContent::Cancelled { sha, .. } => assert_eq!(sha, "2222"),
+            content => panic!("unexpected report: {:?}", content),
+        }
+    }
+}
@@ -7,11 +7,14 @@ use log::*;
 use snafu::{ensure, ResultExt, Snafu};
 use ssh2::{Channel, ExtendedData, Session};
 
+use crate::executor::{Cancellable, Executor};
+
 // TODO: Probably want to do this more `struct`ured
 // TODO: Add time to log
 pub type Log = Vec<(String, String)>;
 
 #[derive(Debug, Snafu)]
+#[snafu(visibility(pub(crate)))]
 pub enum Error {
     Ssh { source: ssh2::Error },
     Io { source: io::Error },
@@ -41,11 +44,22 @@ impl Remote {
             log: Vec::new(),
         })
     }
+}
+
+impl Executor for Remote {
+    /// Installs git and runner via apt/scp (assumes a Debian based image).
+    fn install_tools(&mut self) -> Result<(), Error> {
+        self.execute_command("sudo apt update")?;
+        self.execute_command("sudo apt install -y git")?;
+        // Required for CancellableCommand atm
+        self.upload_file(Path::new("runner-bin"), Path::new("runner"), 0o777)?;
+        self.execute_command("sudo mv runner /usr/bin/runner")
+    }
 
     /// Executes a command on the remote. This blocks until the command finishes and the whole
     /// output was read. The command is executed by the default shell on the remote (probably bash)
     /// so commands like `echo 123 && echo abc` are valid.
-    pub fn execute_command(&mut self, command: &str) -> Result<(), Error> {
+    fn execute_command(&mut self, command: &str) -> Result<(), Error> {
         self.log.push((command.to_string(), String::new()));
 
         let mut channel = self.session.channel_session().context(Ssh)?;
@@ -75,11 +89,11 @@ impl Remote {
     // TODO: This currently behaves differently than the normal `execute_command` due to the runner
     //       implementation detail (bash isn't used for execution). That's also the reason for the
     //       separate (unergonomic) `env` parameter.
-    pub fn execute_cancellable_command(
-        &mut self,
+    fn execute_cancellable_command<'a>(
+        &'a mut self,
         command: &str,
         env: &str,
-    ) -> Result<CancellableCommand, Error> {
+    ) -> Result<Box<dyn Cancellable + 'a>, Error> {
         // TODO: Would like to just send signals over ssh which is actually part of the SSH
         //       specification; Unfortunately nobody implemented that part for a long time and
         //       OpenSSH just did so recently:
@@ -107,14 +121,14 @@ impl Remote {
         debug!("Executing cancellable command: {}", command);
         channel.exec(&command).context(Ssh)?;
 
-        Ok(CancellableCommand {
+        Ok(Box::new(CancellableCommand {
             channel,
             log: &mut self.log,
             session: &mut self.session,
-        })
+        }))
     }
 
-    pub fn upload_file(
+    fn upload_file(
         &mut self,
         local_path: &Path,
         remote_path: &Path,
@@ -135,7 +149,7 @@ impl Remote {
         Ok(())
     }
 
-    pub fn download_file(&mut self, remote_path: &Path) -> Result<Vec<u8>, Error> {
+    fn download_file(&mut self, remote_path: &Path) -> Result<Vec<u8>, Error> {
         debug!("Downloading file {}", remote_path.display());
         let (mut remote_file, stat) = self.session.scp_recv(remote_path).context(Ssh)?;
         let mut contents = Vec::with_capacity(stat.size() as usize);
@@ -143,19 +157,19 @@ impl Remote {
         Ok(contents)
     }
 
-    pub fn into_log(self) -> Log {
+    fn into_log(self: Box<Self>) -> Log {
         self.log
     }
 }
 
-pub struct CancellableCommand<'a> {
+struct CancellableCommand<'a> {
     channel: Channel,
     session: &'a mut Session,
     log: &'a mut Log,
 }
 
-impl CancellableCommand<'_> {
-    pub fn is_running(&mut self) -> bool {
+impl Cancellable for CancellableCommand<'_> {
+    fn is_running(&mut self) -> bool {
         // TODO: This feels like a horrible hack but I'm unable to find another API for this...
         self.session.set_blocking(false);
         let mut buf = [];
@@ -169,7 +183,7 @@ impl CancellableCommand<'_> {
         is_running
     }
 
-    pub fn cancel(mut self) -> Result<(), Error> {
+    fn cancel(mut self: Box<Self>) -> Result<(), Error> {
         // Close stdin which causes runner to kill the command
         self.channel.send_eof().context(Ssh)?;
 
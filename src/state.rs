@@ -0,0 +1,23 @@
+use std::sync::Arc;
+
+use crossbeam_channel::Sender;
+use hubcaps::Github;
+
+use crate::config::GitHubConfig;
+use crate::deliveries::DeliveryLog;
+use crate::history::History;
+use crate::queue::JobQueue;
+use crate::worker::Report;
+
+/// Everything the handlers need to process webhook deliveries and manage jobs
+#[derive(Clone)]
+pub struct AppState {
+    pub config: GitHubConfig,
+    /// Upper bound for the number of packets requested via bot commands
+    pub max_packets: usize,
+    pub github: Github,
+    pub job_queue: Arc<JobQueue>,
+    pub history: Arc<History>,
+    pub report_sender: Sender<Report>,
+    pub deliveries: Arc<DeliveryLog>,
+}
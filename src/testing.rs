@@ -0,0 +1,134 @@
+//! Fakes and helpers shared by the unit tests and the end-to-end tests (which enable them via the
+//! `testing` feature)
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::config::PacketFormat;
+use crate::executor::{Cancellable, Executor};
+use crate::pcap_tester::build_frame;
+use crate::remote::{self, Log};
+use crate::vm::{self, VmProvider, Vms};
+use crate::worker::PCAP_FILE;
+
+/// Executor which pretends that every command succeeds and serves files from memory
+#[derive(Default)]
+pub struct FakeExecutor {
+    /// Downloadable files by their name (regardless of the directory they're requested from)
+    pub files: HashMap<String, Vec<u8>>,
+    pub log: Log,
+}
+
+impl Executor for FakeExecutor {
+    fn execute_command(&mut self, command: &str) -> Result<(), remote::Error> {
+        self.log.push((command.to_string(), String::new()));
+        Ok(())
+    }
+
+    fn execute_cancellable_command<'a>(
+        &'a mut self,
+        command: &str,
+        env: &str,
+    ) -> Result<Box<dyn Cancellable + 'a>, remote::Error> {
+        self.log
+            .push((format!("{}; {}", env, command), String::new()));
+        Ok(Box::new(FakeCommand))
+    }
+
+    fn upload_file(&mut self, _: &Path, _: &Path, _: i32) -> Result<(), remote::Error> {
+        Ok(())
+    }
+
+    fn download_file(&mut self, remote_path: &Path) -> Result<Vec<u8>, remote::Error> {
+        remote_path
+            .file_name()
+            .and_then(|name| self.files.get(&*name.to_string_lossy()))
+            .cloned()
+            .ok_or_else(|| remote::Error::Io {
+                source: io::ErrorKind::NotFound.into(),
+            })
+    }
+
+    fn into_log(self: Box<Self>) -> Log {
+        self.log
+    }
+}
+
+/// Command which has already finished when it's started
+pub struct FakeCommand;
+
+impl Cancellable for FakeCommand {
+    fn is_running(&mut self) -> bool {
+        false
+    }
+
+    fn cancel(self: Box<Self>) -> Result<(), remote::Error> {
+        Ok(())
+    }
+}
+
+/// Provides fake VMs whose pcap VM always returns the same capture (if any)
+#[derive(Default)]
+pub struct FakeVmProvider {
+    pub pcap: Option<Vec<u8>>,
+}
+
+impl VmProvider for FakeVmProvider {
+    fn spawn_vms(&self) -> Result<Vms, vm::Error> {
+        let mut pcap = FakeExecutor::default();
+        if let Some(capture) = &self.pcap {
+            pcap.files.insert(PCAP_FILE.to_string(), capture.clone());
+        }
+        Ok(Vms::Executors {
+            pktgen: Box::new(FakeExecutor::default()),
+            fwd: Box::new(FakeExecutor::default()),
+            pcap: Box::new(pcap),
+        })
+    }
+
+    fn clean_environment(&self) -> Result<(), vm::Error> {
+        Ok(())
+    }
+}
+
+/// Returns an empty directory for the test `name` of `kind` (e.g. `queue`) which isn't shared with
+/// other test processes.
+pub fn temp_dir(kind: &str, name: &str) -> PathBuf {
+    let directory =
+        std::env::temp_dir().join(format!("ixy-ci-{}-{}-{}", kind, std::process::id(), name));
+    let _ = fs::remove_dir_all(&directory);
+    fs::create_dir_all(&directory).unwrap();
+    directory
+}
+
+/// Builds a pcap file with the packets which ixy's pktgen sends for the given sequence numbers.
+pub fn build_pcap(seq_nums: &[u32]) -> Vec<u8> {
+    build_pcap_with_format(seq_nums, &PacketFormat::default())
+}
+
+/// Builds a pcap file with packets of the given format for the given sequence numbers.
+pub fn build_pcap_with_format(seq_nums: &[u32], format: &PacketFormat) -> Vec<u8> {
+    let frames: Vec<_> = seq_nums
+        .iter()
+        .map(|seq_num| build_frame(format, *seq_num))
+        .collect();
+    build_pcap_from_frames(&frames)
+}
+
+/// Builds a pcap file containing the given ethernet frames.
+pub fn build_pcap_from_frames(frames: &[Vec<u8>]) -> Vec<u8> {
+    let mut pcap = Vec::new();
+    // Global header: magic, version 2.4, timezone, sigfigs, snaplen, ethernet link type
+    for value in &[0xa1b2_c3d4, 0x0004_0002, 0, 0, 65535, 1] {
+        pcap.extend_from_slice(&u32::to_le_bytes(*value));
+    }
+    for frame in frames {
+        for value in &[0, 0, frame.len() as u32, frame.len() as u32] {
+            pcap.extend_from_slice(&u32::to_le_bytes(*value));
+        }
+        pcap.extend_from_slice(frame);
+    }
+    pcap
+}
@@ -0,0 +1,134 @@
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use snafu::{ResultExt, Snafu};
+
+use crate::config::{PciAddresses, VmBackend};
+use crate::executor::Executor;
+use crate::libvirt::Libvirt;
+use crate::netns::{Namespace, Namespaces};
+use crate::openstack::OpenStack;
+use crate::{libvirt, netns, openstack};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("An OpenStack error occurred: {}", source))]
+    OpenStackError { source: openstack::Error },
+    #[snafu(display("A libvirt error occurred: {}", source))]
+    LibvirtError { source: libvirt::Error },
+    #[snafu(display("A network namespace error occurred: {}", source))]
+    NamespaceError { source: netns::Error },
+}
+
+/// Addresses under which the spawned VMs are reachable via SSH
+#[derive(Debug, Clone, Copy)]
+pub struct VmAddresses {
+    pub pktgen: IpAddr,
+    pub fwd: IpAddr,
+    pub pcap: IpAddr,
+}
+
+/// Describes how the worker can reach the spawned VMs
+pub enum Vms {
+    Ssh {
+        addresses: VmAddresses,
+        ssh_login: String,
+        private_key_path: PathBuf,
+    },
+    Namespaces {
+        pktgen: Namespace,
+        fwd: Namespace,
+        pcap: Namespace,
+    },
+    /// VMs which can be used directly (e.g. fakes for tests)
+    Executors {
+        pktgen: Box<dyn Executor>,
+        fwd: Box<dyn Executor>,
+        pcap: Box<dyn Executor>,
+    },
+}
+
+/// Spawns the three VMs of the test topology:
+/// `pktgen` <-> `fwd` <-> `pcap`
+pub trait VmProvider {
+    fn spawn_vms(&self) -> Result<Vms, Error>;
+    /// Deletes all VMs and networks belonging to this provider (also those left over from a crash).
+    fn clean_environment(&self) -> Result<(), Error>;
+}
+
+/// Creates the VM provider for the worker with the given slot. It's called on the worker's own
+/// thread as providers don't need to be `Send`.
+pub type VmProviderFactory = Arc<dyn Fn(usize) -> Box<dyn VmProvider> + Send + Sync>;
+
+/// Creates the VM provider for a worker. Each worker slot uses its own set of VMs and networks.
+pub fn new_provider(
+    backend: &VmBackend,
+    slot: usize,
+    pci_addresses: &PciAddresses,
+) -> Result<Box<dyn VmProvider>, Error> {
+    Ok(match backend {
+        VmBackend::OpenStack(config) => {
+            Box::new(OpenStack::new(config.clone(), slot).context(OpenStackError)?)
+        }
+        VmBackend::Libvirt(config) => Box::new(Libvirt::new(config.clone(), slot, pci_addresses)),
+        VmBackend::Namespaces(config) => Box::new(Namespaces::new(config.clone(), slot)),
+    })
+}
+
+/// Removes leftovers of previous runs of all worker slots.
+pub fn clean_all(
+    backend: &VmBackend,
+    slots: usize,
+    pci_addresses: &PciAddresses,
+) -> Result<(), Error> {
+    match backend {
+        VmBackend::OpenStack(config) => openstack::clean_all(config, slots).context(OpenStackError),
+        VmBackend::Libvirt(_) | VmBackend::Namespaces(_) => {
+            for slot in 0..slots {
+                new_provider(backend, slot, pci_addresses)?.clean_environment()?;
+            }
+            Ok(())
+        }
+    }
+}
+
+impl VmProvider for OpenStack {
+    fn spawn_vms(&self) -> Result<Vms, Error> {
+        let (pktgen, fwd, pcap) = OpenStack::spawn_vms(self).context(OpenStackError)?;
+        Ok(Vms::Ssh {
+            addresses: VmAddresses { pktgen, fwd, pcap },
+            ssh_login: self.config.ssh_login.clone(),
+            private_key_path: self.config.private_key_path.clone(),
+        })
+    }
+
+    fn clean_environment(&self) -> Result<(), Error> {
+        OpenStack::clean_environment(self).context(OpenStackError)
+    }
+}
+
+impl VmProvider for Libvirt {
+    fn spawn_vms(&self) -> Result<Vms, Error> {
+        Ok(Vms::Ssh {
+            addresses: Libvirt::spawn_vms(self).context(LibvirtError)?,
+            ssh_login: self.config.ssh_login.clone(),
+            private_key_path: self.config.private_key_path.clone(),
+        })
+    }
+
+    fn clean_environment(&self) -> Result<(), Error> {
+        Libvirt::clean_environment(self).context(LibvirtError)
+    }
+}
+
+impl VmProvider for Namespaces {
+    fn spawn_vms(&self) -> Result<Vms, Error> {
+        let (pktgen, fwd, pcap) = self.spawn_namespaces().context(NamespaceError)?;
+        Ok(Vms::Namespaces { pktgen, fwd, pcap })
+    }
+
+    fn clean_environment(&self) -> Result<(), Error> {
+        Namespaces::clean_environment(self).context(NamespaceError)
+    }
+}
@@ -1,19 +1,27 @@
+use std::cell::Cell;
 use std::io;
 use std::net::IpAddr;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use chrono::{SecondsFormat, Utc};
-use crossbeam_channel::{Receiver, Sender};
+use crossbeam_channel::Sender;
 use log::*;
-use snafu::{ResultExt, Snafu};
-
-use crate::config::{OpenStackConfig, Repository, RepositoryConfig, TestConfig};
-use crate::openstack::OpenStack;
+use serde::{Deserialize, Serialize};
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
+use url::Url;
+
+use crate::config::{Repository, RepositoryConfig, TestConfig};
+use crate::executor::{Executor, LocalExecutor};
+use crate::history::{History, JobRecord, Phase};
+use crate::netns::Namespace;
+use crate::queue::{JobId, JobQueue, QueuedJob};
 use crate::remote::{self, Log, Remote};
-use crate::{openstack, pcap_tester, utility};
+use crate::vm::{self, VmProvider, Vms};
+use crate::{pcap_tester, utility};
 
-const PCAP_FILE: &str = "capture.pcap";
+pub(crate) const PCAP_FILE: &str = "capture.pcap";
 const PCAP_TIMEOUT: Duration = Duration::from_secs(15);
 
 const SSH_MAX_RETRIES: usize = 10;
@@ -25,13 +33,15 @@ pub enum TestError {
     FetchRepositoryConfig { source: reqwest::Error },
     #[snafu(display("Failed to parse CI config: {}", source))]
     ConfigError { source: toml::de::Error },
+    #[snafu(display("Unknown scenario `{}` (not defined in ixy-ci.toml)", scenario))]
+    UnknownScenario { scenario: String },
     #[snafu(display("Failed to connect to VM {} ({})", vm, source))]
     ConnectVm {
         vm: &'static str,
         source: remote::Error,
     },
-    #[snafu(display("An OpenStack error occurred: {}", source))]
-    OpenStackError { source: openstack::Error },
+    #[snafu(display("A VM error occurred: {}", source))]
+    VmError { source: vm::Error },
     #[snafu(display("Failed to save test output: {}", source))]
     SaveTestOutput { source: io::Error },
     #[snafu(display("An error occured while performing tests: {}", source))]
@@ -39,6 +49,32 @@ pub enum TestError {
         source: PerformTestError,
         test_output: TestOutput,
     },
+    #[snafu(display("The test was cancelled"))]
+    Cancelled,
+    #[snafu(display("The test was superseded by a test of {}", sha))]
+    Superseded { sha: String },
+}
+
+impl TestError {
+    /// Returns a short identifier of the error's cause
+    pub fn kind(&self) -> &'static str {
+        match self {
+            TestError::FetchRepositoryConfig { .. } => "fetch_repository_config",
+            TestError::ConfigError { .. } => "config_error",
+            TestError::UnknownScenario { .. } => "unknown_scenario",
+            TestError::ConnectVm { .. } => "connect_vm",
+            TestError::VmError { .. } => "vm_error",
+            TestError::SaveTestOutput { .. } => "save_test_output",
+            TestError::PerformTest { source, .. } => match source {
+                PerformTestError::PrepareVm { .. } => "prepare_vm",
+                PerformTestError::RemoteError { .. } => "remote_error",
+                PerformTestError::TestPcap { .. } => "test_pcap",
+                PerformTestError::Interrupted => "cancelled",
+            },
+            TestError::Cancelled => "cancelled",
+            TestError::Superseded { .. } => "superseded",
+        }
+    }
 }
 
 #[derive(Debug, Snafu)]
@@ -49,19 +85,32 @@ pub enum PerformTestError {
     RemoteError { source: remote::Error },
     #[snafu(display("pcap test error: {}", source))]
     TestPcap { source: pcap_tester::Error },
+    /// The job was cancelled while the test was running
+    #[snafu(display("The test was cancelled"))]
+    Interrupted,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Job {
     TestPullRequest {
         repository: Repository,
         fork_user: String,
         fork_branch: String,
         pull_request_id: u64,
+        sha: String,
+        #[serde(default)]
+        requested_by: String,
+        #[serde(default)]
+        options: TestOptions,
     },
     TestBranch {
         repository: Repository,
         branch: String,
+        sha: String,
+        #[serde(default)]
+        requested_by: String,
+        #[serde(default)]
+        options: TestOptions,
     },
     Ping {
         repository: Repository,
@@ -69,110 +118,284 @@ pub enum Job {
     },
 }
 
+impl Job {
+    /// Returns the repository, target and commit of test jobs
+    pub fn test_target(&self) -> Option<(&Repository, TestTarget, &str)> {
+        match self {
+            Job::TestPullRequest {
+                repository,
+                pull_request_id,
+                sha,
+                ..
+            } => Some((repository, TestTarget::PullRequest(*pull_request_id), sha)),
+            Job::TestBranch {
+                repository,
+                branch,
+                sha,
+                ..
+            } => Some((repository, TestTarget::Branch(branch.clone()), sha)),
+            Job::Ping { .. } => None,
+        }
+    }
+
+    /// Returns whether this job tests `target` of `repository`
+    pub fn tests(&self, repository: &Repository, target: &TestTarget) -> bool {
+        self.test_target()
+            .map(|(job_repository, job_target, _)| {
+                job_repository == repository && job_target == *target
+            })
+            .unwrap_or(false)
+    }
+
+    /// Returns the report which should be published when this job has been added to the queue
+    pub fn queued_report(&self) -> Option<Report> {
+        self.test_target()
+            .map(|(repository, test_target, sha)| Report {
+                repository: repository.clone(),
+                content: ReportContent::Queued {
+                    test_target,
+                    sha: sha.to_string(),
+                },
+            })
+    }
+
+    /// Returns the report which should be published when this job has been cancelled (or
+    /// superseded by a job testing the given commit)
+    pub fn cancelled_report(&self, superseded_by: Option<String>) -> Option<Report> {
+        self.test_target()
+            .map(|(repository, test_target, sha)| Report {
+                repository: repository.clone(),
+                content: ReportContent::Cancelled {
+                    test_target,
+                    sha: sha.to_string(),
+                    superseded_by,
+                },
+            })
+    }
+}
+
+/// Options which can be given when requesting a test (e.g. `@ixy-ci test packets=1000000`)
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TestOptions {
+    /// Overrides the configured number of packets
+    pub packets: Option<usize>,
+    /// Name of a scenario defined in the repository's `ixy-ci.toml`
+    pub scenario: Option<String>,
+}
+
+impl TestOptions {
+    /// Fills the options which aren't set with those of `other`.
+    pub fn or(self, other: TestOptions) -> TestOptions {
+        TestOptions {
+            packets: self.packets.or(other.packets),
+            scenario: self.scenario.or(other.scenario),
+        }
+    }
+}
+
 pub struct Worker {
     log_directory: PathBuf,
-    job_receiver: Receiver<Job>,
+    job_queue: Arc<JobQueue>,
+    history: Arc<History>,
+    current_job: Cell<Option<JobId>>,
     report_sender: Sender<Report>,
-    openstack: OpenStack,
+    vm_provider: Box<dyn VmProvider>,
     test_config: TestConfig,
+    /// Base URL from which the `ixy-ci.toml` of tested commits is fetched
+    raw_url: Url,
 }
 
 impl Worker {
     pub fn new(
-        job_queue_size: usize,
         log_directory: PathBuf,
-        openstack: OpenStackConfig,
+        vm_provider: Box<dyn VmProvider>,
         test_config: TestConfig,
-    ) -> (Worker, Sender<Job>, Receiver<Report>) {
-        let (job_sender, job_receiver) = crossbeam_channel::bounded(job_queue_size);
-        let (report_sender, future_receiver) = crossbeam_channel::unbounded();
-        (
-            Worker {
-                log_directory,
-                job_receiver,
-                report_sender,
-                openstack: OpenStack::new(openstack).expect("failed to connect to OpenStack"),
-                test_config,
-            },
-            job_sender,
-            future_receiver,
-        )
+        raw_url: Url,
+        job_queue: Arc<JobQueue>,
+        history: Arc<History>,
+        report_sender: Sender<Report>,
+    ) -> Worker {
+        Worker {
+            log_directory,
+            job_queue,
+            history,
+            current_job: Cell::new(None),
+            report_sender,
+            vm_provider,
+            test_config,
+            raw_url,
+        }
     }
 
     pub fn run(&self) {
-        while let Ok(job) = self.job_receiver.recv() {
-            match job {
+        loop {
+            let queued_job = self.job_queue.pop();
+            if queued_job.interrupted {
+                warn!("Retrying job {} which was interrupted", queued_job.id);
+            }
+            match &queued_job.job {
                 Job::Ping {
                     repository,
                     issue_id,
                 } => {
-                    self.report_sender
-                        .send(Report {
-                            repository,
-                            content: ReportContent::Pong { issue_id },
-                        })
-                        .expect("failed to send report");
-                }
-                Job::TestBranch { repository, branch } => {
-                    info!("Testing branch: {}:{}", repository, branch);
-                    let result = self.test_repository(&repository, &branch);
-                    self.report_sender
-                        .send(Report {
-                            repository,
-                            content: ReportContent::TestResult {
-                                result,
-                                test_target: TestTarget::Branch(branch),
+                    send_report(
+                        &self.report_sender,
+                        Report {
+                            repository: repository.clone(),
+                            content: ReportContent::Pong {
+                                issue_id: *issue_id,
                             },
-                        })
-                        .expect("failed to send report");
-                }
-                Job::TestPullRequest {
-                    repository,
-                    fork_user,
-                    fork_branch,
-                    pull_request_id,
-                } => {
-                    info!(
-                        "Testing pull request: {}'s fork of {} (branch {})",
-                        fork_user, repository, fork_branch
+                        },
                     );
-                    let test_repo = Repository {
-                        user: fork_user,
-                        name: repository.name.clone(),
-                    };
-                    self.report_sender
-                        .send(Report {
-                            repository,
-                            content: ReportContent::TestResult {
-                                result: self.test_repository(&test_repo, &fork_branch),
-                                test_target: TestTarget::PullRequest(pull_request_id),
-                            },
-                        })
-                        .expect("failed to send report");
+                }
+                Job::TestBranch { .. } | Job::TestPullRequest { .. } => self.test_job(&queued_job),
+            }
+            self.job_queue.finish(queued_job.id);
+        }
+    }
+
+    fn test_job(&self, queued_job: &QueuedJob) {
+        let (test_repo, requested_by, options) = match &queued_job.job {
+            Job::TestBranch {
+                repository,
+                branch,
+                sha,
+                requested_by,
+                options,
+            } => {
+                info!("Testing branch: {}:{} ({})", repository, branch, sha);
+                (repository.clone(), requested_by, options)
+            }
+            Job::TestPullRequest {
+                repository,
+                fork_user,
+                fork_branch,
+                sha,
+                requested_by,
+                options,
+                ..
+            } => {
+                info!(
+                    "Testing pull request: {}'s fork of {} (branch {}, {})",
+                    fork_user, repository, fork_branch, sha
+                );
+                let test_repo = Repository {
+                    user: fork_user.clone(),
+                    name: repository.name.clone(),
+                };
+                (test_repo, requested_by, options)
+            }
+            Job::Ping { .. } => unreachable!("not a test job"),
+        };
+        let (repository, test_target, sha) = queued_job.job.test_target().unwrap();
+
+        send_report(
+            &self.report_sender,
+            Report {
+                repository: repository.clone(),
+                content: ReportContent::Started {
+                    test_target: test_target.clone(),
+                    sha: sha.to_string(),
+                },
+            },
+        );
+
+        self.history.start(JobRecord::new(
+            queued_job,
+            repository.clone(),
+            test_target.clone(),
+            sha.to_string(),
+            requested_by.clone(),
+            options.clone(),
+        ));
+        self.current_job.set(Some(queued_job.id));
+        let result = match self.test_repository(&test_repo, sha, options) {
+            Err(TestError::Cancelled) => match self.job_queue.superseded_by(queued_job.id) {
+                Some(sha) => Err(TestError::Superseded { sha }),
+                None => Err(TestError::Cancelled),
+            },
+            result => result,
+        };
+        self.current_job.set(None);
+        self.history.finish(queued_job.id, &result);
+
+        let content = match result {
+            Err(TestError::Cancelled) => {
+                info!("Job {} was cancelled", queued_job.id);
+                ReportContent::Cancelled {
+                    test_target,
+                    sha: sha.to_string(),
+                    superseded_by: None,
                 }
             }
+            Err(TestError::Superseded { sha: superseded_by }) => {
+                info!("Job {} was superseded", queued_job.id);
+                ReportContent::Cancelled {
+                    test_target,
+                    sha: sha.to_string(),
+                    superseded_by: Some(superseded_by),
+                }
+            }
+            result => ReportContent::TestResult {
+                result,
+                test_target,
+                sha: sha.to_string(),
+            },
+        };
+        send_report(
+            &self.report_sender,
+            Report {
+                repository: repository.clone(),
+                content,
+            },
+        );
+    }
+
+    /// Marks the start of the next phase of the currently running job. Returns `false` if the job
+    /// has been cancelled and shouldn't continue.
+    fn enter_phase(&self, phase: Phase) -> bool {
+        if let Some(id) = self.current_job.get() {
+            self.history.enter_phase(id, phase);
         }
+        !self.is_cancelled()
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.current_job
+            .get()
+            .map(|id| self.job_queue.is_cancelled(id))
+            .unwrap_or(false)
     }
 
     fn test_repository(
         &self,
         repository: &Repository,
-        branch: &str,
+        sha: &str,
+        options: &TestOptions,
     ) -> Result<TestOutput, TestError> {
-        let repo_config = fetch_repo_config(repository, branch)?;
+        ensure!(self.enter_phase(Phase::FetchConfig), Cancelled);
+        let mut repo_config = fetch_repo_config(&self.raw_url, repository, sha)?;
+        if let Some(scenario) = &options.scenario {
+            repo_config = repo_config
+                .with_scenario(scenario)
+                .context(UnknownScenario { scenario })?;
+        }
+        let packets = options.packets.unwrap_or(self.test_config.packets);
 
-        let (ip_pktgen, ip_fwd, ip_pcap) = self.openstack.spawn_vms().context(OpenStackError)?;
+        ensure!(self.enter_phase(Phase::SpawnVms), Cancelled);
+        let vms = self.vm_provider.spawn_vms().context(VmError)?;
 
-        let ret = self.test_repository_inner(
-            &repo_config,
-            repository,
-            branch,
-            ip_pktgen,
-            ip_fwd,
-            ip_pcap,
-        );
+        let ret = self.test_repository_inner(&repo_config, repository, sha, packets, vms);
 
-        self.openstack.clean_environment().context(OpenStackError)?;
+        // Clean up even if the job has been cancelled. A failed clean up doesn't change the result
+        // of the test.
+        self.enter_phase(Phase::CleanUp);
+        if let Err(e) = self.vm_provider.clean_environment() {
+            error!(
+                "Failed to clean up after testing {} ({}): {}",
+                repository, sha, e
+            );
+        }
 
         ret
     }
@@ -181,42 +404,50 @@ impl Worker {
         &self,
         repo_config: &RepositoryConfig,
         repository: &Repository,
-        branch: &str,
-        ip_pktgen: IpAddr,
-        ip_fwd: IpAddr,
-        ip_pcap: IpAddr,
+        sha: &str,
+        packets: usize,
+        vms: Vms,
     ) -> Result<TestOutput, TestError> {
-        info!("Using VMs at: {}, {}, {}", ip_pktgen, ip_fwd, ip_pcap);
-
-        trace!("Connecting to pktgen");
-        let vm_pktgen = utility::retry(SSH_MAX_RETRIES, SSH_RETRY_DELAY, || {
-            Remote::connect(
-                (ip_pktgen, 22).into(),
-                &self.openstack.config.ssh_login,
-                &self.openstack.config.private_key_path,
-            )
-        })
-        .context(ConnectVm { vm: "pktgen" })?;
-
-        trace!("Connecting to fwd");
-        let vm_fwd = utility::retry(SSH_MAX_RETRIES, SSH_RETRY_DELAY, || {
-            Remote::connect(
-                (ip_fwd, 22).into(),
-                &self.openstack.config.ssh_login,
-                &self.openstack.config.private_key_path,
-            )
-        })
-        .context(ConnectVm { vm: "fwd" })?;
-
-        trace!("Connecting to pcap");
-        let vm_pcap = utility::retry(SSH_MAX_RETRIES, SSH_RETRY_DELAY, || {
-            Remote::connect(
-                (ip_pcap, 22).into(),
-                &self.openstack.config.ssh_login,
-                &self.openstack.config.private_key_path,
-            )
-        })
-        .context(ConnectVm { vm: "pcap" })?;
+        ensure!(self.enter_phase(Phase::ConnectVms), Cancelled);
+        let (vm_pktgen, vm_fwd, vm_pcap) = match vms {
+            Vms::Ssh {
+                addresses,
+                ssh_login,
+                private_key_path,
+            } => {
+                info!(
+                    "Using VMs at: {}, {}, {}",
+                    addresses.pktgen, addresses.fwd, addresses.pcap
+                );
+                let connect = |vm: &'static str, address: IpAddr| {
+                    trace!("Connecting to {}", vm);
+                    utility::retry(SSH_MAX_RETRIES, SSH_RETRY_DELAY, || {
+                        Remote::connect((address, 22).into(), &ssh_login, &private_key_path)
+                    })
+                    .map(|remote| Box::new(remote) as Box<dyn Executor>)
+                    .context(ConnectVm { vm })
+                };
+                (
+                    connect("pktgen", addresses.pktgen)?,
+                    connect("fwd", addresses.fwd)?,
+                    connect("pcap", addresses.pcap)?,
+                )
+            }
+            Vms::Namespaces { pktgen, fwd, pcap } => {
+                info!(
+                    "Using namespaces: {}, {}, {}",
+                    pktgen.name, fwd.name, pcap.name
+                );
+                let executor = |namespace: Namespace| -> Box<dyn Executor> {
+                    Box::new(LocalExecutor::new(
+                        namespace.directory,
+                        Some(namespace.name),
+                    ))
+                };
+                (executor(pktgen), executor(fwd), executor(pcap))
+            }
+            Vms::Executors { pktgen, fwd, pcap } => (pktgen, fwd, pcap),
+        };
 
         let mut context = TestContext {
             vm_pktgen,
@@ -224,10 +455,13 @@ impl Worker {
             vm_pcap,
             pcap: None,
         };
-        let result = self.perform_test(&repository, branch, &repo_config, &mut context);
+        let result = self.perform_test(&repository, sha, &repo_config, packets, &mut context);
+        if let Err(PerformTestError::Interrupted) = result {
+            return Err(TestError::Cancelled);
+        }
 
         let test_output = self
-            .save_test_output(repository, branch, context)
+            .save_test_output(repository, sha, context)
             .context(SaveTestOutput)?;
 
         match result {
@@ -239,24 +473,27 @@ impl Worker {
     fn perform_test(
         &self,
         repository: &Repository,
-        branch: &str,
+        sha: &str,
         repo_config: &RepositoryConfig,
+        packets: usize,
         context: &mut TestContext,
     ) -> Result<(), PerformTestError> {
         info!("Preparing VMs");
+        ensure!(self.enter_phase(Phase::PrepareVms), Interrupted);
         prepare_vms(
             &mut [
-                &mut context.vm_pktgen,
-                &mut context.vm_fwd,
-                &mut context.vm_pcap,
+                &mut *context.vm_pktgen,
+                &mut *context.vm_fwd,
+                &mut *context.vm_pcap,
             ],
             &repo_config.build,
             &repository,
-            &branch,
+            sha,
         )
         .context(PrepareVm)?;
 
         info!("Starting pcap");
+        ensure!(self.enter_phase(Phase::RunTest), Interrupted);
         let env = format!(
             "PCI_ADDR_PKTGEN={}; \
              PCI_ADDR_FWD_SRC={}; \
@@ -270,7 +507,7 @@ impl Worker {
             self.test_config.pci_addresses.fwd_dst,
             self.test_config.pci_addresses.pcap,
             PCAP_FILE,
-            self.test_config.packets,
+            packets,
             repository.name
         );
 
@@ -294,6 +531,10 @@ impl Worker {
                 error!("pcap timeout");
                 break;
             }
+            if self.is_cancelled() {
+                info!("Stopping test which was cancelled");
+                break;
+            }
             std::thread::sleep(Duration::from_millis(200));
         }
         info!("pcap finished in {:?}", start_time.elapsed());
@@ -301,19 +542,23 @@ impl Worker {
         pcap_cmd.cancel().context(RemoteError)?;
         fwd_cmd.cancel().context(RemoteError)?;
         pktgen_cmd.cancel().context(RemoteError)?;
+        ensure!(!self.is_cancelled(), Interrupted);
 
         let pcap = context
             .vm_pcap
-            .download_file(Path::new(&format!(
-                "/home/{}/{}/{}",
-                self.openstack.config.ssh_login, repository.name, PCAP_FILE
-            )))
+            .download_file(Path::new(&format!("{}/{}", repository.name, PCAP_FILE)))
             .context(RemoteError)?;
         context.pcap = Some(pcap);
 
-        pcap_tester::test_pcap(&context.pcap.as_ref().unwrap(), self.test_config.packets)
-            .context(TestPcap)?;
-        info!("pcap test succeeded");
+        ensure!(self.enter_phase(Phase::CheckPcap), Interrupted);
+        let report = pcap_tester::test_pcap(
+            &context.pcap.as_ref().unwrap(),
+            packets,
+            &repo_config.packet_format,
+            &self.test_config.criteria,
+        )
+        .context(TestPcap)?;
+        info!("pcap test succeeded:\n{}", report);
 
         Ok(())
     }
@@ -321,14 +566,14 @@ impl Worker {
     fn save_test_output(
         &self,
         repository: &Repository,
-        branch: &str,
+        sha: &str,
         context: TestContext,
     ) -> Result<TestOutput, io::Error> {
         let file_name = format!(
             "{}__{}__{}__{}",
             repository.user,
             repository.name,
-            branch,
+            sha,
             Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true)
         );
         let log_file = file_name.clone() + ".log";
@@ -342,6 +587,7 @@ impl Worker {
             })
             .transpose()?;
         Ok(TestOutput {
+            sha: sha.to_string(),
             log_pktgen: context.vm_pktgen.into_log(),
             log_fwd: context.vm_fwd.into_log(),
             log_pcap: context.vm_pcap.into_log(),
@@ -351,10 +597,16 @@ impl Worker {
     }
 }
 
-fn fetch_repo_config(repository: &Repository, branch: &str) -> Result<RepositoryConfig, TestError> {
+fn fetch_repo_config(
+    raw_url: &Url,
+    repository: &Repository,
+    sha: &str,
+) -> Result<RepositoryConfig, TestError> {
     let toml = reqwest::get(&format!(
-        "https://raw.githubusercontent.com/{}/{}/ixy-ci.toml",
-        repository, branch
+        "{}/{}/{}/ixy-ci.toml",
+        raw_url.as_str().trim_end_matches('/'),
+        repository,
+        sha
     ))
     .and_then(|r| r.error_for_status()?.text())
     .context(FetchRepositoryConfig)?;
@@ -362,37 +614,42 @@ fn fetch_repo_config(repository: &Repository, branch: &str) -> Result<Repository
 }
 
 fn prepare_vms(
-    remotes: &mut [&mut Remote],
+    remotes: &mut [&mut dyn Executor],
     setup: &[String],
     repository: &Repository,
-    branch: &str,
+    sha: &str,
 ) -> Result<(), remote::Error> {
     for remote in remotes {
-        remote.execute_command("sudo apt update")?;
-        remote.execute_command("sudo apt install -y git")?;
+        remote.install_tools()?;
+        // Check out the exact commit so that all VMs test the same code even if the branch was
+        // updated in the meantime
+        remote.execute_command(&format!(
+            "git clone https://github.com/{} --no-checkout",
+            repository
+        ))?;
+        remote.execute_command(&format!("cd {} && git checkout {}", repository.name, sha))?;
         remote.execute_command(&format!(
-            "git clone https://github.com/{} --branch {} --single-branch --recurse-submodules",
-            repository, branch
+            "cd {} && git submodule update --init --recursive",
+            repository.name
         ))?;
         for step in setup {
             remote.execute_command(&format!("cd {} && {}", repository.name, step))?;
         }
-        // Required for CancellableCommand atm
-        remote.upload_file(Path::new("runner-bin"), Path::new("runner"), 0o777)?;
-        remote.execute_command("sudo mv runner /usr/bin/runner")?;
     }
     Ok(())
 }
 
 pub struct TestContext {
-    pub vm_pktgen: Remote,
-    pub vm_fwd: Remote,
-    pub vm_pcap: Remote,
+    pub vm_pktgen: Box<dyn Executor>,
+    pub vm_fwd: Box<dyn Executor>,
+    pub vm_pcap: Box<dyn Executor>,
     pub pcap: Option<Vec<u8>>,
 }
 
 #[derive(Debug)]
 pub struct TestOutput {
+    pub sha: String,
+
     pub log_pktgen: Log,
     pub log_fwd: Log,
     pub log_pcap: Log,
@@ -407,20 +664,211 @@ pub struct Report {
     pub content: ReportContent,
 }
 
+/// Hands `report` over to the publisher. If the publisher is gone the report is only logged as
+/// lost instead of panicking (which could poison the job queue's lock).
+pub fn send_report(report_sender: &Sender<Report>, report: Report) {
+    if let Err(e) = report_sender.send(report) {
+        error!(
+            "Dropping report for {} as the publisher is gone",
+            e.into_inner().repository
+        );
+    }
+}
+
 #[derive(Debug)]
 #[allow(clippy::large_enum_variant)]
 pub enum ReportContent {
     Pong {
         issue_id: u64,
     },
+    Queued {
+        test_target: TestTarget,
+        sha: String,
+    },
+    Started {
+        test_target: TestTarget,
+        sha: String,
+    },
     TestResult {
         result: Result<TestOutput, TestError>,
         test_target: TestTarget,
+        sha: String,
+    },
+    /// The job was cancelled before it finished
+    Cancelled {
+        test_target: TestTarget,
+        sha: String,
+        /// The commit whose test replaced this one
+        superseded_by: Option<String>,
     },
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum TestTarget {
     PullRequest(u64),
     Branch(String),
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::config::{PacketFormat, PcapCriteria, PciAddresses};
+    use crate::queue::Cancelled;
+    use crate::testing::{self, build_pcap, FakeExecutor, FakeVmProvider};
+
+    fn test_worker(name: &str) -> Worker {
+        let directory = testing::temp_dir("worker", name);
+        Worker {
+            log_directory: directory.clone(),
+            job_queue: Arc::new(JobQueue::open(directory.join("queue.json"), 10, false).unwrap()),
+            history: Arc::new(History::open(directory.join("history.jsonl")).unwrap()),
+            current_job: Cell::new(None),
+            report_sender: crossbeam_channel::unbounded().0,
+            vm_provider: Box::new(FakeVmProvider::default()),
+            raw_url: Url::parse("https://raw.githubusercontent.com").unwrap(),
+            test_config: TestConfig {
+                packets: 100,
+                max_packets: 1000,
+                pci_addresses: PciAddresses {
+                    pktgen: "0000:00:06.0".to_string(),
+                    fwd_src: "0000:00:06.0".to_string(),
+                    fwd_dst: "0000:00:07.0".to_string(),
+                    pcap: "0000:00:06.0".to_string(),
+                },
+                criteria: PcapCriteria::default(),
+            },
+        }
+    }
+
+    fn test_context(pcap: Option<Vec<u8>>) -> TestContext {
+        let mut vm_pcap = FakeExecutor::default();
+        if let Some(pcap) = pcap {
+            vm_pcap.files.insert(PCAP_FILE.to_string(), pcap);
+        }
+        TestContext {
+            vm_pktgen: Box::new(FakeExecutor::default()),
+            vm_fwd: Box::new(FakeExecutor::default()),
+            vm_pcap: Box::new(vm_pcap),
+            pcap: None,
+        }
+    }
+
+    fn perform_test(
+        worker: &Worker,
+        packets: usize,
+        context: &mut TestContext,
+    ) -> Result<(), PerformTestError> {
+        let repository = Repository {
+            user: "ixy-languages".to_string(),
+            name: "ixy.rs".to_string(),
+        };
+        let repo_config = RepositoryConfig {
+            build: vec!["cargo build --release".to_string()],
+            pktgen: "./pktgen".to_string(),
+            fwd: "./fwd".to_string(),
+            pcap: "./pcap".to_string(),
+            scenarios: HashMap::new(),
+            packet_format: PacketFormat::default(),
+        };
+        worker.perform_test(&repository, "0123abcd", &repo_config, packets, context)
+    }
+
+    #[test]
+    fn test_perform_test() {
+        let worker = test_worker("perform");
+        let seq_nums: Vec<_> = (0..100).collect();
+        let mut context = test_context(Some(build_pcap(&seq_nums)));
+        perform_test(&worker, 100, &mut context).unwrap();
+        assert!(context.pcap.is_some());
+
+        let log = context.vm_pcap.into_log();
+        let commands: Vec<_> = log.iter().map(|(command, _)| command.as_str()).collect();
+        assert_eq!(
+            &commands[..4],
+            &[
+                "git clone https://github.com/ixy-languages/ixy.rs --no-checkout",
+                "cd ixy.rs && git checkout 0123abcd",
+                "cd ixy.rs && git submodule update --init --recursive",
+                "cd ixy.rs && cargo build --release",
+            ]
+        );
+        assert!(commands[4].contains("PCAP_N=100"));
+        assert!(commands[4].ends_with("sudo ./pcap"));
+    }
+
+    #[test]
+    fn test_perform_test_bad_pcap() {
+        let worker = test_worker("bad_pcap");
+        let seq_nums: Vec<_> = (0..50).collect();
+        let mut context = test_context(Some(build_pcap(&seq_nums)));
+        match perform_test(&worker, 100, &mut context) {
+            Err(PerformTestError::TestPcap { .. }) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+        // The pcap is kept so that it can be inspected
+        assert!(context.pcap.is_some());
+    }
+
+    #[test]
+    fn test_perform_test_packets() {
+        let worker = test_worker("packets");
+        let seq_nums: Vec<_> = (0..50).collect();
+        let mut context = test_context(Some(build_pcap(&seq_nums)));
+        perform_test(&worker, 50, &mut context).unwrap();
+
+        let log = context.vm_pcap.into_log();
+        assert!(log[4].0.contains("PCAP_N=50"));
+    }
+
+    #[test]
+    fn test_perform_test_cancelled() {
+        let worker = test_worker("cancelled");
+        let report_sender = crossbeam_channel::unbounded().0;
+        worker
+            .job_queue
+            .push(
+                Job::Ping {
+                    repository: Repository {
+                        user: "ixy-languages".to_string(),
+                        name: "ixy.rs".to_string(),
+                    },
+                    issue_id: 1,
+                },
+                &report_sender,
+            )
+            .unwrap();
+        let queued_job = worker.job_queue.pop();
+        worker.current_job.set(Some(queued_job.id));
+        assert_eq!(
+            worker.job_queue.cancel(|_| true, &report_sender),
+            Cancelled {
+                queued: 0,
+                running: 1
+            }
+        );
+
+        let seq_nums: Vec<_> = (0..100).collect();
+        let mut context = test_context(Some(build_pcap(&seq_nums)));
+        match perform_test(&worker, 100, &mut context) {
+            Err(PerformTestError::Interrupted) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+        assert!(context.vm_pcap.into_log().is_empty());
+
+        worker.job_queue.finish(queued_job.id);
+        assert!(!worker.job_queue.is_cancelled(queued_job.id));
+    }
+
+    #[test]
+    fn test_perform_test_missing_pcap() {
+        let worker = test_worker("missing_pcap");
+        let mut context = test_context(None);
+        match perform_test(&worker, 100, &mut context) {
+            Err(PerformTestError::RemoteError { .. }) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+}
@@ -0,0 +1,421 @@
+//! End-to-end tests which run ixy-ci against a mock GitHub server and fake VMs
+
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use actix_web::web::{self, Data};
+use actix_web::{App, HttpRequest, HttpResponse, HttpServer};
+use ring::{digest, hmac};
+use serde_json::json;
+
+use ixy_ci::config::Config;
+use ixy_ci::testing::{self, build_pcap, FakeVmProvider};
+use ixy_ci::vm::VmProvider;
+
+const WEBHOOK_SECRET: &str = "secret";
+const ADMIN_TOKEN: &str = "admin";
+const PACKETS: u32 = 100;
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+const REPOSITORY_CONFIG: &str = r#"
+build = ["make"]
+pktgen = "./pktgen $PCI_ADDR_PKTGEN"
+fwd = "./fwd $PCI_ADDR_FWD_SRC $PCI_ADDR_FWD_DST"
+pcap = "./pcap $PCI_ADDR_PCAP $PCAP_OUT $PCAP_N"
+"#;
+
+/// A request received by the mock GitHub server
+#[derive(Debug, Clone)]
+struct Request {
+    method: String,
+    path: String,
+    body: String,
+}
+
+type Requests = Arc<Mutex<Vec<Request>>>;
+/// How many of the next API requests fail (without being recorded)
+type Failures = Arc<Mutex<usize>>;
+
+/// Records all API requests (apart from failing ones) and serves `REPOSITORY_CONFIG` for every
+/// `ixy-ci.toml`. The response bodies aren't valid API objects so ixy-ci logs errors after its
+/// requests which is fine as we only care about what it sends.
+fn github_request(
+    request: HttpRequest,
+    body: String,
+    requests: Data<Requests>,
+    failures: Data<Failures>,
+) -> HttpResponse {
+    if request.path().ends_with("/ixy-ci.toml") {
+        return HttpResponse::Ok().body(REPOSITORY_CONFIG);
+    }
+    {
+        let mut failures = failures.lock().unwrap();
+        if *failures > 0 {
+            *failures -= 1;
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+    requests.lock().unwrap().push(Request {
+        method: request.method().to_string(),
+        path: request.path().to_string(),
+        body,
+    });
+    HttpResponse::Created()
+        .content_type("application/json")
+        .body("{}")
+}
+
+fn start_mock_github(requests: Requests, failures: Failures) -> String {
+    let port = free_port();
+    thread::spawn(move || {
+        let sys = actix_rt::System::new("mock-github");
+        HttpServer::new(move || {
+            App::new()
+                .data(requests.clone())
+                .data(failures.clone())
+                .default_service(web::route().to(github_request))
+        })
+        .bind(("127.0.0.1", port))?
+        .start();
+        sys.run()
+    });
+    wait_for_port(port);
+    format!("http://127.0.0.1:{}", port)
+}
+
+struct Instance {
+    url: String,
+    requests: Requests,
+    failures: Failures,
+}
+
+impl Instance {
+    /// Starts ixy-ci whose tests capture the packets with the given sequence numbers.
+    fn start(name: &str, seq_nums: &[u32]) -> Instance {
+        let directory = testing::temp_dir("e2e", name);
+
+        let (requests, failures) = (Requests::default(), Failures::default());
+        let github_url = start_mock_github(requests.clone(), failures.clone());
+        let port = free_port();
+        let config: Config = toml::from_str(&format!(
+            r#"
+            bind_address = "127.0.0.1:{port}"
+            public_url = "http://127.0.0.1:{port}"
+            job_queue_size = 10
+            log_directory = "{directory}/logs"
+            data_directory = "{directory}/data"
+
+            [admin]
+            token = "{admin_token}"
+
+            [github]
+            bot_name = "ixy-ci"
+            api_token = "token"
+            api_url = "{github_url}"
+            raw_url = "{github_url}/raw"
+            test_pull_requests = ["ixy-languages/ixy.rs"]
+
+            [github.webhook_secrets]
+            "ixy-languages/ixy.rs" = "{secret}"
+
+            [test]
+            packets = {packets}
+
+            [test.pci_addresses]
+            pktgen = "0000:00:06.0"
+            fwd_src = "0000:00:06.0"
+            fwd_dst = "0000:00:07.0"
+            pcap = "0000:00:06.0"
+            "#,
+            port = port,
+            directory = directory.display(),
+            github_url = github_url,
+            secret = WEBHOOK_SECRET,
+            admin_token = ADMIN_TOKEN,
+            packets = PACKETS,
+        ))
+        .unwrap();
+
+        let pcap = build_pcap(seq_nums);
+        thread::spawn(move || {
+            ixy_ci::run(
+                config,
+                Arc::new(move |_: usize| {
+                    Box::new(FakeVmProvider {
+                        pcap: Some(pcap.clone()),
+                    }) as Box<dyn VmProvider>
+                }),
+            )
+        });
+        wait_for_port(port);
+
+        Instance {
+            url: format!("http://127.0.0.1:{}", port),
+            requests,
+            failures,
+        }
+    }
+
+    fn post_webhook(&self, event: &str, payload: &serde_json::Value) -> reqwest::StatusCode {
+        static DELIVERY_ID: AtomicUsize = AtomicUsize::new(0);
+        let delivery_id = DELIVERY_ID.fetch_add(1, Ordering::SeqCst).to_string();
+        self.post_delivery(event, payload, &delivery_id)
+    }
+
+    fn post_delivery(
+        &self,
+        event: &str,
+        payload: &serde_json::Value,
+        delivery_id: &str,
+    ) -> reqwest::StatusCode {
+        let body = payload.to_string();
+        let key = hmac::SigningKey::new(&digest::SHA256, WEBHOOK_SECRET.as_bytes());
+        let signature = hex::encode(hmac::sign(&key, body.as_bytes()).as_ref());
+        self.post_signed_webhook(event, body, &format!("sha256={}", signature), delivery_id)
+    }
+
+    fn post_signed_webhook(
+        &self,
+        event: &str,
+        body: String,
+        signature: &str,
+        delivery_id: &str,
+    ) -> reqwest::StatusCode {
+        reqwest::Client::new()
+            .post(&format!("{}/github/webhook", self.url))
+            .header("Content-Type", "application/json")
+            .header("X-GitHub-Event", event)
+            .header("X-GitHub-Delivery", delivery_id)
+            .header("X-Hub-Signature-256", signature)
+            .body(body)
+            .send()
+            .unwrap()
+            .status()
+    }
+
+    fn replay_delivery(&self, delivery_id: &str, admin_token: &str) -> reqwest::StatusCode {
+        reqwest::Client::new()
+            .post(&format!(
+                "{}/api/deliveries/{}/replay",
+                self.url, delivery_id
+            ))
+            .header("Authorization", format!("Bearer {}", admin_token))
+            .send()
+            .unwrap()
+            .status()
+    }
+
+    fn count_requests<P: Fn(&Request) -> bool>(&self, predicate: P) -> usize {
+        self.requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|r| predicate(r))
+            .count()
+    }
+
+    /// Waits until the mock GitHub server received a request matching `predicate`.
+    fn wait_for_request<P: Fn(&Request) -> bool>(&self, predicate: P) -> Request {
+        let start = Instant::now();
+        while start.elapsed() < TIMEOUT {
+            if let Some(request) = self.requests.lock().unwrap().iter().find(|r| predicate(r)) {
+                return request.clone();
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        panic!(
+            "no matching request received; got: {:#?}",
+            self.requests.lock().unwrap()
+        );
+    }
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+fn wait_for_port(port: u16) {
+    let start = Instant::now();
+    while TcpStream::connect(("127.0.0.1", port)).is_err() {
+        assert!(start.elapsed() < TIMEOUT, "server didn't start");
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+fn repository() -> serde_json::Value {
+    json!({
+        "name": "ixy.rs",
+        "owner": { "login": "ixy-languages" },
+    })
+}
+
+fn pull_request_opened(sha: &str) -> serde_json::Value {
+    json!({
+        "action": "opened",
+        "number": 3,
+        "pull_request": {
+            "head": {
+                "ref": "feature",
+                "sha": sha,
+                "user": { "login": "contributor" },
+            },
+            "user": { "login": "contributor" },
+        },
+        "repository": repository(),
+        "sender": { "login": "contributor" },
+    })
+}
+
+fn comment(body: &str) -> serde_json::Value {
+    json!({
+        "action": "created",
+        "issue": { "id": 1234, "number": 7 },
+        "repository": repository(),
+        "comment": {
+            "body": body,
+            "user": { "login": "contributor" },
+        },
+    })
+}
+
+fn ping_comment() -> serde_json::Value {
+    comment("@ixy-ci ping")
+}
+
+fn is_ping_response(request: &Request) -> bool {
+    request.method == "POST" && request.path == "/repos/ixy-languages/ixy.rs/issues/7/comments"
+}
+
+#[test]
+fn test_ping() {
+    let instance = Instance::start("ping", &[]);
+    let status = instance.post_webhook("issue_comment", &ping_comment());
+    assert!(status.is_success());
+
+    let request = instance.wait_for_request(is_ping_response);
+    assert!(request.body.contains("pong"));
+}
+
+#[test]
+fn test_commands() {
+    let instance = Instance::start("commands", &[]);
+    let status = instance.post_webhook("issue_comment", &comment("@ixy-ci deploy"));
+    assert!(status.is_success());
+    let request = instance.wait_for_request(|r| r.body.contains("unknown command"));
+    assert!(request.body.contains("`@ixy-ci help`"));
+
+    let status = instance.post_webhook("issue_comment", &comment("@ixy-ci status"));
+    assert!(status.is_success());
+    instance.wait_for_request(|r| r.body.contains("hasn't been tested yet"));
+}
+
+#[test]
+fn test_pull_request_passed() {
+    let seq_nums: Vec<_> = (0..PACKETS).collect();
+    let instance = Instance::start("passed", &seq_nums);
+    let status = instance.post_webhook("pull_request", &pull_request_opened("c0ffee"));
+    assert!(status.is_success());
+
+    instance.wait_for_request(|r| {
+        r.path == "/repos/ixy-languages/ixy.rs/statuses/c0ffee" && r.body.contains("pending")
+    });
+    instance.wait_for_request(|r| {
+        r.path == "/repos/ixy-languages/ixy.rs/statuses/c0ffee" && r.body.contains("success")
+    });
+    let comment =
+        instance.wait_for_request(|r| r.path == "/repos/ixy-languages/ixy.rs/issues/3/comments");
+    assert!(comment.body.contains("Test of c0ffee __passed__!"));
+}
+
+#[test]
+fn test_pull_request_failed() {
+    // fwd dropped half of the packets
+    let seq_nums: Vec<_> = (0..PACKETS).step_by(2).collect();
+    let instance = Instance::start("failed", &seq_nums);
+    let status = instance.post_webhook("pull_request", &pull_request_opened("deadbeef"));
+    assert!(status.is_success());
+
+    instance.wait_for_request(|r| {
+        r.path == "/repos/ixy-languages/ixy.rs/statuses/deadbeef" && r.body.contains("failure")
+    });
+    let comment =
+        instance.wait_for_request(|r| r.path == "/repos/ixy-languages/ixy.rs/issues/3/comments");
+    assert!(comment.body.contains("Test of deadbeef __failed__!"));
+}
+
+#[test]
+fn test_invalid_signature() {
+    let instance = Instance::start("invalid_signature", &[]);
+    let body = pull_request_opened("c0ffee").to_string();
+    let status =
+        instance.post_signed_webhook("pull_request", body, "sha256=0123456789abcdef", "invalid");
+    assert_eq!(status, reqwest::StatusCode::UNAUTHORIZED);
+
+    thread::sleep(Duration::from_millis(500));
+    assert!(instance.requests.lock().unwrap().is_empty());
+}
+
+#[test]
+fn test_duplicate_delivery() {
+    let instance = Instance::start("duplicate_delivery", &[]);
+    assert!(instance
+        .post_delivery("issue_comment", &ping_comment(), "duplicate")
+        .is_success());
+    instance.wait_for_request(is_ping_response);
+
+    // A redelivery is acknowledged but not processed again
+    assert!(instance
+        .post_delivery("issue_comment", &ping_comment(), "duplicate")
+        .is_success());
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(instance.count_requests(is_ping_response), 1);
+}
+
+#[test]
+fn test_failed_delivery() {
+    let instance = Instance::start("failed_delivery", &[]);
+    let is_help = |r: &Request| is_ping_response(r) && r.body.contains("Available commands");
+
+    // Answering the comment fails
+    *instance.failures.lock().unwrap() = 1;
+    assert_eq!(
+        instance.post_delivery("issue_comment", &comment("@ixy-ci help"), "failed"),
+        reqwest::StatusCode::INTERNAL_SERVER_ERROR
+    );
+    assert_eq!(instance.count_requests(is_help), 0);
+
+    // so GitHub's redelivery isn't ignored as a duplicate
+    instance.post_delivery("issue_comment", &comment("@ixy-ci help"), "failed");
+    instance.wait_for_request(is_help);
+}
+
+#[test]
+fn test_replay_delivery() {
+    let instance = Instance::start("replay_delivery", &[]);
+    assert!(instance
+        .post_delivery("issue_comment", &ping_comment(), "replay")
+        .is_success());
+    instance.wait_for_request(is_ping_response);
+
+    assert_eq!(
+        instance.replay_delivery("replay", "wrong token"),
+        reqwest::StatusCode::UNAUTHORIZED
+    );
+    assert_eq!(
+        instance.replay_delivery("unknown", ADMIN_TOKEN),
+        reqwest::StatusCode::NOT_FOUND
+    );
+    assert!(instance.replay_delivery("replay", ADMIN_TOKEN).is_success());
+    let start = Instant::now();
+    while instance.count_requests(is_ping_response) < 2 {
+        assert!(start.elapsed() < TIMEOUT, "delivery wasn't replayed");
+        thread::sleep(Duration::from_millis(100));
+    }
+}